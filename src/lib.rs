@@ -1,28 +1,155 @@
 use std::{
-    collections::HashMap,
-    ffi::CStr,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{CStr, CString},
     fs::File,
     io::{self, Read, Seek, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    },
     path::{Path, PathBuf},
     process::Command,
     rc::Rc,
     sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
 };
 
 use configparser::ini::Ini;
+use dbus::{
+    blocking::{
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        LocalConnection,
+    },
+    channel::{BusType, Channel},
+    Message,
+};
 use flate2::read::GzDecoder;
-use glib::MainLoop;
+use glib::{ControlFlow, IOCondition, MainLoop, SourceId};
 use libc::c_char;
-use log::{info, warn};
+use log::{debug, error, info, warn};
 type CpuId = (u8, u8, u8);
 
+/// Everything that can go wrong, see [`Error::exit_code`] for how `main` reports each.
+#[derive(Debug)]
+pub enum Error {
+    /// The command line couldn't be parsed.
+    Usage(String),
+    /// Not running as root.
+    NotRoot,
+    /// Root was refused access to `register`, which kernel lockdown does under Secure Boot.
+    PermissionDenied {
+        register: &'static str,
+        source: io::Error,
+    },
+    /// The msr kernel module is missing and couldn't be loaded.
+    MsrModule,
+    /// The kernel config couldn't be read or lacks a required option.
+    Kernel(String),
+    /// The CPU isn't one of the supported Intel models.
+    UnsupportedCpu(String),
+    /// The config file couldn't be read or is invalid.
+    Config(String),
+    /// A register name, plane or bit range that doesn't exist.
+    InvalidArgument(String),
+    /// The CPU doesn't implement the named feature.
+    Unsupported(&'static str),
+    /// The lock bit of `register` is set, so it can't be changed until reset.
+    Locked(&'static str),
+    Read {
+        register: &'static str,
+        source: io::Error,
+    },
+    Write {
+        register: &'static str,
+        source: MsrWriteError,
+    },
+    /// A write was accepted but reading back shows another value.
+    NotApplied(String),
+}
+
+impl Error {
+    /// Process exit code for the error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::Config(_) => 3,
+            Error::NotRoot | Error::PermissionDenied { .. } => 4,
+            Error::Read { source, .. }
+            | Error::Write {
+                source: MsrWriteError::Io { source, .. },
+                ..
+            } if source.kind() == io::ErrorKind::PermissionDenied => 4,
+            Error::MsrModule | Error::Kernel(_) => 5,
+            Error::UnsupportedCpu(_) => 6,
+            _ => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Usage(msg) => write!(f, "{msg}"),
+            Error::NotRoot => write!(f, "No root no party. Try again with sudo."),
+            Error::PermissionDenied { register, source } => write!(
+                f,
+                "Unable to read {register}: {source}. Try to disable Secure Boot."
+            ),
+            Error::MsrModule => write!(f, "Unable to load the msr module."),
+            Error::Kernel(msg) => write!(f, "Bad kernel config: {msg}"),
+            Error::UnsupportedCpu(msg) => write!(f, "{msg}"),
+            Error::Config(msg) => write!(f, "Invalid configuration: {msg}"),
+            Error::InvalidArgument(msg) => write!(f, "{msg}"),
+            Error::Unsupported(feature) => write!(f, "{feature} is not supported by this CPU"),
+            Error::Locked(register) => write!(f, "{register} is locked on this CPU"),
+            Error::Read { register, source } => write!(f, "Unable to read {register}: {source}"),
+            Error::Write { register, source } => write!(f, "Unable to write {register}: {source}"),
+            Error::NotApplied(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PermissionDenied { source, .. } | Error::Read { source, .. } => Some(source),
+            Error::Write { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Looks up the name and address of the register called `arg`.
+fn msr_addr(arg: &str) -> Result<(&'static str, u64), Error> {
+    MSR_DICT
+        .get_key_value(arg)
+        .map(|(name, (addr, _))| (*name, *addr))
+        .ok_or_else(|| Error::InvalidArgument(format!("unknown register {arg}")))
+}
+
+/// The cpus to access register `arg` on: one per instance of it, as given by its scope.
+fn register_cpus(msr: &dyn MsrBackend, arg: &str) -> Result<Vec<usize>, Error> {
+    let (_, scope) = MSR_DICT
+        .get(arg)
+        .ok_or_else(|| Error::InvalidArgument(format!("unknown register {arg}")))?;
+    Ok(scope_cpus(msr, *scope))
+}
+
+/// Command-line options.
 #[derive(Clone, Debug)]
-struct Config {
-    config: PathBuf,
-    debug: bool,
-    force: bool,
-    log: Option<Rc<File>>,
-    monitor_ms: u64,
+pub struct Config {
+    pub config: PathBuf,
+    pub debug: bool,
+    pub force: bool,
+    pub log: Option<Arc<File>>,
+    pub monitor: bool,
+    pub monitor_ms: u64,
+    pub dry_run: bool,
+    /// Dump the registers instead of running the daemon.
+    pub regs: bool,
+    pub raw: bool,
+    pub json: bool,
 }
 
 impl Config {
@@ -30,9 +157,14 @@ impl Config {
         Config {
             log: None,
             debug: false,
+            monitor: false,
             monitor_ms: 1000,
             config: PathBuf::from("/etc/throttled.conf"),
             force: false,
+            dry_run: false,
+            regs: false,
+            raw: false,
+            json: false,
         }
     }
 }
@@ -133,22 +265,35 @@ static CPUMAP: LazyLock<HashMap<CpuId, &'static str>> = LazyLock::new(|| {
 //     CPUMAP.get(x).copied()
 // }
 
+/// Which cpus share one instance of a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsrScope {
+    /// Every logical cpu has its own.
+    Thread,
+    /// Shared by the hyperthreads of a core.
+    Core,
+    /// Shared by every cpu of a physical package.
+    Package,
+}
+
 // fn msr_dict(x:&'static str) -> Option<u64> {
-static MSR_DICT: LazyLock<HashMap<&'static str, u64>> = LazyLock::new(|| {
+static MSR_DICT: LazyLock<HashMap<&'static str, (u64, MsrScope)>> = LazyLock::new(|| {
+    use MsrScope::*;
     HashMap::from([
-        ("MSR_PLATFORM_INFO", 0xCE),
-        ("MSR_OC_MAILBOX", 0x150),
-        ("IA32_PERF_STATUS", 0x198),
-        ("IA32_THERM_STATUS", 0x19C),
-        ("MSR_TEMPERATURE_TARGET", 0x1A2),
-        ("MSR_POWER_CTL", 0x1FC),
-        ("MSR_RAPL_POWER_UNIT", 0x606),
-        ("MSR_PKG_POWER_LIMIT", 0x610),
-        ("MSR_INTEL_PKG_ENERGY_STATUS", 0x611),
-        ("MSR_DRAM_ENERGY_STATUS", 0x619),
-        ("MSR_PP1_ENERGY_STATUS", 0x641),
-        ("MSR_CONFIG_TDP_CONTROL", 0x64B),
-        ("IA32_HWP_REQUEST", 0x774),
+        ("MSR_PLATFORM_INFO", (0xCE, Package)),
+        ("MSR_OC_MAILBOX", (0x150, Package)),
+        ("IA32_PERF_STATUS", (0x198, Thread)),
+        ("IA32_THERM_STATUS", (0x19C, Core)),
+        ("IA32_PACKAGE_THERM_STATUS", (0x1B1, Package)),
+        ("MSR_TEMPERATURE_TARGET", (0x1A2, Package)),
+        ("MSR_POWER_CTL", (0x1FC, Core)),
+        ("MSR_RAPL_POWER_UNIT", (0x606, Package)),
+        ("MSR_PKG_POWER_LIMIT", (0x610, Package)),
+        ("MSR_INTEL_PKG_ENERGY_STATUS", (0x611, Package)),
+        ("MSR_DRAM_ENERGY_STATUS", (0x619, Package)),
+        ("MSR_PP1_ENERGY_STATUS", (0x641, Package)),
+        ("MSR_CONFIG_TDP_CONTROL", (0x64B, Package)),
+        ("IA32_HWP_REQUEST", (0x774, Thread)),
     ])
 });
 //     MSR_DICT.get(x).copied()
@@ -177,17 +322,181 @@ static CURRENT_PLANES: LazyLock<HashMap<&'static str, u64>> =
 //     CURRENT_PLANES.get(x).copied()
 // }
 
-const TRIP_TEMP_RANGE: (i32, i32) = (40, 97);
 const UNDERVOLT_KEYS: [&str; 3] = ["UNDERVOLT", "UNDERVOLT.AC", "UNDERVOLT.BATTERY"];
 const ICCMAX_KEYS: [&str; 3] = ["ICCMAX", "ICCMAX.AC", "ICCMAX.BATTERY"];
+/// Largest current limit the 10 bit, 1/4 A encoding can represent.
+const ICCMAX_MAX_A: f64 = 0x3FF as f64 * 0.25;
+/// Largest undervolt the 11 bit signed, 1/1.024 mV encoding can represent, in its steps.
+const UNDERVOLT_MIN_STEPS: i64 = -0x3FF;
+/// Longest accepted `Update_Rate_s`, a day.
+const UPDATE_RATE_MAX_S: f64 = 86400.0;
 const HWP_PERFOLRMANCE_VALUE: i32 = 0x20;
 const HWP_DEFAULT_VALUE: i32 = 0x80;
 const HWP_INTERVAL: i32 = 60;
 const TRIP_TEMP_MIN: f64 = 40.0;
 const TRIP_TEMP_MAX: f64 = 97.0;
 
-fn parse_args() -> Config {
-    Config::new()
+const USAGE: &str = "\
+usage: rsthrottled [-h] [--version] [--debug] [--config CONFIG] [--force]
+                   [--log /path/to/file] [--monitor [update_rate]] [--dry-run]
+       rsthrottled regs [--raw] [--json]
+
+Stop Intel CPU throttling
+
+options:
+  -h, --help            show this help message and exit
+  --version             show program's version number and exit
+  --debug               add some debug info and additional checks
+  --config CONFIG       override default config file path
+  --force               bypass compatibility checks (EXPERTS only)
+  --log /path/to/file   log to file instead of stdout
+  --monitor [update_rate]
+                        realtime monitoring of throttling causes (default 1s)
+  --dry-run             print the register writes the config leads to and exit
+                        without writing them
+
+regs options:
+  --raw                 print register values in hex without decoding them
+  --json                print registers as JSON";
+
+/// Shortest monitor period.
+const MONITOR_MIN_MS: u64 = 100;
+
+/// What the command line asks for.
+#[derive(Debug)]
+pub enum ParsedArgs {
+    Run(Config),
+    Help,
+    Version,
+}
+
+fn parse_args() -> Result<ParsedArgs, Error> {
+    parse_args_from(std::env::args().skip(1)).map_err(|msg| {
+        eprintln!("{}", USAGE.lines().take(2).collect::<Vec<_>>().join("\n"));
+        eprintln!("rsthrottled: error: {msg}");
+        Error::Usage(msg)
+    })
+}
+
+/// Parses the arguments following the program name.
+pub fn parse_args_from(args: impl IntoIterator<Item = String>) -> Result<ParsedArgs, String> {
+    let mut config = Config::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next_if(|next| !next.starts_with('-')))
+                .ok_or_else(|| format!("argument {name}: expected one argument"))
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(ParsedArgs::Help),
+            "--version" => return Ok(ParsedArgs::Version),
+            "--debug" => config.debug = true,
+            "--force" => config.force = true,
+            "--dry-run" => config.dry_run = true,
+            "regs" => config.regs = true,
+            "--raw" => config.raw = true,
+            "--json" => config.json = true,
+            "--config" => config.config = PathBuf::from(value("--config")?),
+            "--log" => {
+                let path = value("--log")?;
+                let file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| format!("argument --log: can't open '{path}': {e}"))?;
+                config.log = Some(Arc::new(file));
+            }
+            "--monitor" => {
+                config.monitor = true;
+                if let Ok(rate) = value("--monitor") {
+                    let secs: f64 = rate
+                        .parse()
+                        .ok()
+                        .filter(|secs: &f64| secs.is_finite() && *secs > 0.0)
+                        .ok_or_else(|| {
+                            format!("argument --monitor: invalid update rate: '{rate}'")
+                        })?;
+                    config.monitor_ms = ((secs * 1000.0).round() as u64).max(MONITOR_MIN_MS);
+                }
+            }
+            _ => return Err(format!("unrecognized arguments: {flag}")),
+        }
+    }
+    if (config.raw || config.json) && !config.regs {
+        let flag = if config.raw { "--raw" } else { "--json" };
+        return Err(format!("argument {flag}: only valid with regs"));
+    }
+    if config.regs && (config.dry_run || config.monitor) {
+        let flag = if config.dry_run {
+            "--dry-run"
+        } else {
+            "--monitor"
+        };
+        return Err(format!("argument {flag}: not valid with regs"));
+    }
+    Ok(ParsedArgs::Run(config))
+}
+
+/// Prints log records like throttled does, to `--log` if given or stdout otherwise.
+struct Logger {
+    level: log::LevelFilter,
+    file: Option<Arc<File>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let tag = match record.level() {
+            log::Level::Error => "E",
+            log::Level::Warn => "W",
+            log::Level::Info => "I",
+            log::Level::Debug | log::Level::Trace => "D",
+        };
+        let line = format!("[{tag}] {}\n", record.args());
+        let _ = match &self.file {
+            Some(file) => file.as_ref().write_all(line.as_bytes()),
+            None => io::stdout().write_all(line.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = match &self.file {
+            Some(file) => file.as_ref().flush(),
+            None => io::stdout().flush(),
+        };
+    }
+}
+
+fn init_logger(args: &Config) {
+    let level = if args.debug {
+        log::LevelFilter::Debug
+    } else if args.regs {
+        // keep the dump clean
+        log::LevelFilter::Warn
+    } else {
+        log::LevelFilter::Info
+    };
+    let logger = Logger {
+        level,
+        file: args.log.clone(),
+    };
+    if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+        log::set_max_level(level);
+    }
 }
 
 fn set_msr_allow_writes() {
@@ -209,6 +518,7 @@ fn set_msr_allow_writes() {
 }
 
 fn test_msr_rw_capabilities(
+    msr: &dyn MsrBackend,
     test_msr: Arc<Mutex<bool>>,
     unsupported_features: &mut Vec<&'static str>,
 ) {
@@ -216,287 +526,2600 @@ fn test_msr_rw_capabilities(
         *data = true;
     }
     info!("Testing if undervolt is supported...");
-    let res = get_undervolt(unsupported_features, None, false, test_msr.clone());
-    if res.is_err() {
-        warn!("Undervolt seems not to be supported on your system, disabling.");
-        unsupported_features.push("UNDERVOLT");
+    match get_undervolt(msr, unsupported_features, None, false, test_msr.clone()) {
+        Ok(_) => {}
+        // Refused by DryRunMsr, the undervolt is then planned without knowing the current one.
+        Err(Error::Read { source, .. }) if source.kind() == io::ErrorKind::Unsupported => {
+            info!("Undervolt support can't be tested in a dry run.");
+        }
+        Err(_) => {
+            warn!("Undervolt seems not to be supported on your system, disabling.");
+            unsupported_features.push("UNDERVOLT");
+        }
+    }
+    if readmsr_flat(msr, "IA32_HWP_REQUEST", None, None).is_err() {
+        warn!("HWP seems not to be supported on your system, disabling.");
+        unsupported_features.push("HWP");
     }
     if let Ok(mut data) = test_msr.lock() {
         *data = false;
     }
 }
 
-fn get_power_source() -> ! {
-    todo!()
+/// Which power profile of the config applies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+impl PowerSource {
+    /// Name of the config section holding this source's profile.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerSource::Ac => "AC",
+            PowerSource::Battery => "BATTERY",
+        }
+    }
+
+    fn from_on_battery(on_battery: bool) -> Self {
+        if on_battery {
+            PowerSource::Battery
+        } else {
+            PowerSource::Ac
+        }
+    }
 }
 
-fn get_platform_info() -> ! {
-    todo!()
+impl std::fmt::Display for PowerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-type IniMap = HashMap<String, HashMap<String, Option<String>>>;
+const UPOWER_BUS: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(1);
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+const POWER_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-fn load_config(args: Config) -> Result<IniMap, String> {
-    let mut ini = Ini::new();
-    let map = ini.load(args.config)?;
-    let opts = [
-        "Update_Rate_s",
-        "PL1_Tdp_W",
-        "PL1_Duration_s",
-        "PL2_Tdp_W",
-        "PL2_Duration_S",
-    ];
-    let tt = "Trip_Temp_C";
-    for power_source in ["AC", "BATTERY"] {
-        for option in opts {
-            if let Some(value) = ini.getfloat(power_source, option)? {
-                ini.set(power_source, option, Some(value.max(0.001).to_string()));
-            } else if option == "Update_Rate_s" {
-                fatal("The mandatory \"Update_Rate_s\" parameter is missing.");
-            }
-        }
-
-        if let Some(trip_temp) = ini.getfloat(power_source, tt)? {
-            let valid_trip_temp = trip_temp.clamp(TRIP_TEMP_MIN, TRIP_TEMP_MAX);
-            if valid_trip_temp != trip_temp {
-                warn!("{power_source} trip temp ({trip_temp}) not in valid range: [{TRIP_TEMP_MIN}, {TRIP_TEMP_MAX}], overriding");
-                ini.set(power_source, tt, Some(valid_trip_temp.to_string()));
+/// Connects to the system bus, with a watch fd so it can be driven by the glib loop.
+fn system_bus() -> Option<LocalConnection> {
+    let mut channel = Channel::get_private(BusType::System)
+        .map_err(|e| warn!("Unable to connect to the system bus: {e}"))
+        .ok()?;
+    channel.set_watch_enabled(true);
+    Some(LocalConnection::from(channel))
+}
+
+fn upower_on_battery(bus: &LocalConnection) -> Result<bool, dbus::Error> {
+    bus.with_proxy(UPOWER_BUS, UPOWER_PATH, DBUS_TIMEOUT)
+        .get(UPOWER_BUS, "OnBattery")
+}
+
+/// Reads the power source from the supplies under `root`, laid out like
+/// [`POWER_SUPPLY_PATH`]: on AC if any non-battery supply is online, or if none can be read.
+pub fn sysfs_power_source(root: &Path) -> PowerSource {
+    let mut found = false;
+    for entry in std::fs::read_dir(root).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let kind = std::fs::read_to_string(path.join("type")).unwrap_or_default();
+        if kind.trim() == "Battery" {
+            continue;
+        }
+        if let Ok(online) = std::fs::read_to_string(path.join("online")) {
+            found = true;
+            if online.trim() == "1" {
+                return PowerSource::Ac;
             }
         }
     }
+    if !found {
+        warn!("No valid power detection methods found. Assuming that the system is running on AC power.");
+        return PowerSource::Ac;
+    }
+    PowerSource::Battery
+}
 
-    // validate undervolt settings config
-    for key in UNDERVOLT_KEYS {
-        for plane in VOLTAGE_PLANES.keys() {
-            if !map.contains_key(key) {
-                continue;
-            }
-            let val = ini.getfloat(key, plane)?.unwrap_or_default();
-            let valid_val = val.min(0.0);
-            if val != valid_val {
-                warn!("Invalid {key} {plane} value: {val} (is positive), overriding");
-                ini.set(key, plane, Some(valid_val.to_string()));
-            }
+fn get_power_source(bus: Option<&LocalConnection>) -> PowerSource {
+    match bus.map(upower_on_battery) {
+        Some(Ok(on_battery)) => PowerSource::from_on_battery(on_battery),
+        Some(Err(e)) => {
+            warn!("Unable to query UPower ({e}), falling back to sysfs.");
+            sysfs_power_source(Path::new(POWER_SUPPLY_PATH))
         }
+        None => sysfs_power_source(Path::new(POWER_SUPPLY_PATH)),
     }
+}
 
-    // check if only one of "UNDERVOLT.AC", "UNDERVOLT.BATTERY" is set
-    if map.contains_key(UNDERVOLT_KEYS[1]) || map.contains_key(UNDERVOLT_KEYS[2]) {
-        for key in UNDERVOLT_KEYS.iter().skip(1) {
-            for plane in VOLTAGE_PLANES.keys() {
-                let val = ini.getfloat(key, plane)?;
-                if val.is_none() {
-                    ini.set(key, plane, Some((0.0).to_string()));
+/// Calls `on_change` from the glib loop whenever the power source switches.
+///
+/// Listens to UPower's PropertiesChanged signal, or polls sysfs every
+/// [`POWER_POLL_INTERVAL`] if UPower can't be reached.
+fn watch_power_source(
+    bus: Option<LocalConnection>,
+    current: PowerSource,
+    on_change: impl Fn(PowerSource) + 'static,
+) {
+    let last = Rc::new(Cell::new(current));
+    let notify = move |source: PowerSource| {
+        if last.replace(source) != source {
+            on_change(source);
+        }
+    };
+    let bus = bus.filter(|bus| upower_on_battery(bus).is_ok());
+    let Some(bus) = bus else {
+        info!("Polling {POWER_SUPPLY_PATH} for power source changes.");
+        glib::timeout_add_local(POWER_POLL_INTERVAL, move || {
+            notify(sysfs_power_source(Path::new(POWER_SUPPLY_PATH)));
+            ControlFlow::Continue
+        });
+        return;
+    };
+    let subscribed = bus
+        .with_proxy(UPOWER_BUS, UPOWER_PATH, DBUS_TIMEOUT)
+        .match_signal(
+            move |signal: PropertiesPropertiesChanged, _: &LocalConnection, _: &Message| {
+                if signal.interface_name != UPOWER_BUS {
+                    return true;
+                }
+                match signal.changed_properties.get("OnBattery") {
+                    Some(value) => match dbus::arg::cast::<bool>(&value.0) {
+                        Some(on_battery) => notify(PowerSource::from_on_battery(*on_battery)),
+                        None => warn!("Unexpected OnBattery value from UPower: {value:?}"),
+                    },
+                    None if signal
+                        .invalidated_properties
+                        .iter()
+                        .any(|p| p == "OnBattery") =>
+                    {
+                        notify(sysfs_power_source(Path::new(POWER_SUPPLY_PATH)))
+                    }
+                    None => {}
                 }
+                true
+            },
+        );
+    if let Err(e) = subscribed {
+        warn!("Unable to subscribe to UPower signals: {e}");
+        return;
+    }
+    let fd = bus.channel().watch().fd;
+    glib::unix_fd_add_local(fd, IOCondition::IN, move |_, _| loop {
+        match bus.process(Duration::ZERO) {
+            Ok(true) => continue,
+            Ok(false) => break ControlFlow::Continue,
+            Err(e) => {
+                warn!("Lost connection to the system bus: {e}");
+                break ControlFlow::Break;
             }
         }
-    }
-
-    todo!();
-
-    Ok(map)
+    });
 }
 
-fn get_reg_values() -> ! {
-    todo!()
+/// Capabilities reported by MSR_PLATFORM_INFO.
+///
+/// The register has no voltage override bit: undervolt support is probed
+/// through the OC mailbox by `test_msr_rw_capabilities` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlatformInfo {
+    pub maximum_non_turbo_ratio: u8,
+    pub ppin_cap: bool,
+    /// Turbo ratio limits can be changed.
+    pub programmable_ratio_limit: bool,
+    /// Turbo TDP limits can be changed.
+    pub programmable_tdp_limit: bool,
+    /// The TCC activation offset of MSR_TEMPERATURE_TARGET can be changed.
+    pub programmable_tj_offset: bool,
+    pub low_power_mode: bool,
+    /// Number of configurable TDP levels besides the nominal one.
+    pub config_tdp_levels: u8,
+    pub maximum_efficiency_ratio: u8,
+    pub minimum_operating_ratio: u8,
 }
 
-fn get_undervolt(
-    unsupported_features: &Vec<&'static str>,
-    plane: Option<&'static str>,
-    convert: bool,
-    test_msr: Arc<Mutex<bool>>,
-) -> Result<HashMap<&'static str, i64>, String> {
-    if !unsupported_features.contains(&"UNDERVOLT") {
-        return Err("Undervolt is not supported".to_owned());
-    }
-    let mut out = HashMap::new();
-    let planes = plane
-        .map(|x| {
-            VOLTAGE_PLANES
-                .get_key_value(x)
-                .map(|(k, v)| HashMap::from([(*k, *v)]))
-                .unwrap_or_else(|| {
-                    fatal(&format!("Error: plane {} not found in VOLTAGE_PLANES", x))
-                })
-        })
-        .unwrap_or(VOLTAGE_PLANES.clone());
-    for (k, v) in planes {
-        writemsr("MSR_OC_MAILBOX", 0x8000001000000000 | (v << 40));
-        let read_result = readmsr_flat("MSR_OC_MAILBOX", None, None);
-        let read_value = match read_result {
-            Ok(value) => value & 0xFFFFFFFF,
-            Err(e) => {
-                if test_msr.lock().is_ok_and(|x| *x) {
-                    return Err(format!("raise e: mutex taken {}", e));
-                } else {
-                    match e.kind() {
-                        io::ErrorKind::PermissionDenied => fatal(&format!(
-                            "Unable to read {}. Try to disable Secure Boot.",
-                            e
-                        )),
-                        _ => return Err(format!("Unknown error while reading msr: {}", e)),
-                    }
-                };
-            }
-        };
-        let val = if convert {
-            calc_undervolt_mv(read_value)
-        } else {
-            read_value as i64
-        };
-        out.insert(k, val);
+impl PlatformInfo {
+    pub fn from_msr(value: u64) -> Self {
+        let bits = |offset: u32, mask: u64| ((value >> offset) & mask) as u8;
+        PlatformInfo {
+            maximum_non_turbo_ratio: bits(8, 0xFF),
+            ppin_cap: bits(23, 1) == 1,
+            programmable_ratio_limit: bits(28, 1) == 1,
+            programmable_tdp_limit: bits(29, 1) == 1,
+            programmable_tj_offset: bits(30, 1) == 1,
+            low_power_mode: bits(32, 1) == 1,
+            config_tdp_levels: bits(33, 0x3),
+            maximum_efficiency_ratio: bits(40, 0xFF),
+            minimum_operating_ratio: bits(48, 0xFF),
+        }
     }
-    Ok(out)
 }
 
-fn calc_undervolt_mv(read_value: u64) -> i64 {
-    let offset = ((read_value & 0xFFE00000) >> 21).try_into().unwrap();
-    let res: i32 = if offset <= 0x400 {
-        offset
-    } else {
-        -(0x800 - offset)
-    };
-    ((res as f64) / 1.024).round() as i64
+fn get_platform_info(msr: &dyn MsrBackend) -> Result<PlatformInfo, Error> {
+    readmsr_flat(msr, "MSR_PLATFORM_INFO", None, None).map(PlatformInfo::from_msr)
 }
 
-fn readmsr_flat(arg: &str, from: Option<usize>, to: Option<usize>) -> Result<u64, io::Error> {
-    let from = from.unwrap_or(0);
-    let to = to.unwrap_or(63);
-    assert!(from < to);
-    assert!(to <= 63);
-    // assert!(cpu.is_none_or(|x| (0..cpu_count()).contains(&x)));
-    let cpu_count = cpu_count();
-    let mut msr_list = (0..cpu_count)
-        .map(|i| format!("/dev/cpu/{}/msr", i))
-        .peekable();
-    if let Some(cpu_zero) = msr_list.peek() {
-        if !Path::new(cpu_zero).exists() {
-            let is_msr_loaded = Command::new("modprobe")
-                .arg("msr")
-                .status()
-                .is_ok_and(|exit| exit.success());
-            if !is_msr_loaded {
-                fatal("Unable to load the msr module.");
+/// Takes ownership of the descriptor a syscall returned, or turns its failure into an error.
+fn owned_fd(fd: libc::c_int) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: the kernel just handed out `fd`, nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Reads everything `fd` has whenever it becomes readable, passing each read to `parse` to
+/// collect events. `on_events` gets them `delay` after the first one, so a burst is handled once.
+fn watch_fd<T: 'static>(
+    fd: OwnedFd,
+    delay: Duration,
+    parse: impl Fn(&[u8], &mut Vec<T>) + 'static,
+    on_events: impl Fn(Vec<T>) + 'static,
+) {
+    let on_events = Rc::new(on_events);
+    let pending = Rc::new(RefCell::new(Vec::new()));
+    glib::unix_fd_add_local(fd.as_raw_fd(), IOCondition::IN, move |_, _| {
+        let mut buf = [0u8; 8192];
+        let was_pending = !pending.borrow().is_empty();
+        loop {
+            // Safety: reads into `buf`, which outlives the call.
+            let len = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if len <= 0 {
+                break;
             }
+            parse(&buf[..len as usize], &mut pending.borrow_mut());
         }
+        if !was_pending && !pending.borrow().is_empty() {
+            let on_events = on_events.clone();
+            let pending = pending.clone();
+            glib::timeout_add_local_once(delay, move || on_events(pending.take()));
+        }
+        ControlFlow::Continue
+    });
+}
+
+/// Delay letting an editor finish saving the config file before reloading it.
+const CONFIG_RELOAD_DELAY: Duration = Duration::from_millis(200);
+
+/// Calls `on_change` from the glib loop whenever the file at `path` is written or replaced.
+///
+/// Watches the parent directory, since editors often save by renaming a new file over the old
+/// one.
+fn watch_config(path: &Path, on_change: impl Fn() + 'static) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = CString::new(dir.as_os_str().as_bytes())?;
+    // Safety: takes no pointers.
+    let fd = owned_fd(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+    // Safety: `dir` is a valid C string for the duration of the call.
+    if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+        return Err(io::Error::last_os_error());
     }
-    let mut msr_values = Vec::with_capacity(cpu_count);
-    let mut buffer: [u8; 8] = [0; 8];
-    let arg_addr = *MSR_DICT.get(arg).unwrap();
-    for path in msr_list {
-        let mut fh = File::open(path)?;
-        let _ = fh.seek(io::SeekFrom::Start(arg_addr))?;
-        fh.read_exact(&mut buffer)?;
-        msr_values.push(u64::from_le_bytes(buffer));
-    }
-    match msr_values.as_slice() {
-        [head, tail @ ..] => {
-            if !tail.iter().all(|x| x == head) {
-                warn!(
-                    "multiple values for {} ({:x}) found. This should never happen.",
-                    arg, arg_addr
-                );
+    let parse = move |mut events: &[u8], changed: &mut Vec<()>| {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        while events.len() >= HEADER {
+            // Safety: the kernel only returns whole events.
+            let event: libc::inotify_event = unsafe {
+                events
+                    .as_ptr()
+                    .cast::<libc::inotify_event>()
+                    .read_unaligned()
+            };
+            let end = HEADER + event.len as usize;
+            let event_name =
+                CStr::from_bytes_until_nul(&events[HEADER..end]).map_or(&[][..], CStr::to_bytes);
+            if event_name == name.as_bytes() {
+                changed.push(());
             }
-            Ok(*head)
+            events = &events[end..];
         }
-        [] => fatal("No msr values found"),
+    };
+    watch_fd(fd, CONFIG_RELOAD_DELAY, parse, move |_| on_change());
+    Ok(())
+}
+
+/// Time for a batch of cpus to come online, e.g. when SMT is enabled, before acting on them.
+const CPU_ONLINE_DELAY: Duration = Duration::from_millis(500);
+
+/// Number of the cpu a kernel uevent, `<action>@<devpath>\0<env>...`, reports as online.
+pub fn parse_cpu_online_uevent(msg: &[u8]) -> Option<usize> {
+    let header = msg.split(|&b| b == 0).next()?;
+    let (action, devpath) = std::str::from_utf8(header).ok()?.split_once('@')?;
+    if action != "online" {
+        return None;
+    }
+    devpath
+        .strip_prefix("/devices/system/cpu/cpu")?
+        .parse()
+        .ok()
+}
+
+/// Calls `on_online` with the cpus that came online, listening to kernel uevents.
+fn watch_cpu_online(on_online: impl Fn(&[usize]) + 'static) -> io::Result<()> {
+    // Safety: takes no pointers.
+    let fd = owned_fd(unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    })?;
+    // Safety: sockaddr_nl is plain data, all zeroes is valid.
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    // Multicast group of the uevents sent by the kernel itself.
+    addr.nl_groups = 1;
+    // Safety: `addr` is a sockaddr_nl of the given size, alive for the duration of the call.
+    let bound = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const libc::sockaddr_nl).cast(),
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if bound < 0 {
+        return Err(io::Error::last_os_error());
     }
+    // Each read returns one uevent.
+    let parse = |uevent: &[u8], cpus: &mut Vec<usize>| cpus.extend(parse_cpu_online_uevent(uevent));
+    watch_fd(fd, CPU_ONLINE_DELAY, parse, move |mut cpus| {
+        cpus.sort();
+        cpus.dedup();
+        on_online(&cpus);
+    });
+    Ok(())
 }
 
-fn cpu_count() -> usize {
-    num_cpus::get()
+/// Settings of the `[GENERAL]` section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneralConfig {
+    pub enabled: bool,
+    pub autoreload: bool,
+    /// Also write the power limits to their MCHBAR mirror.
+    pub mchbar_mirror: bool,
+    /// Put back the settings found at startup when exiting.
+    pub restore_on_exit: bool,
 }
 
-fn writemsr(arg: &str, plane: u64) {
-    todo!()
+/// Settings applied while running on one power source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerProfile {
+    pub update_rate_s: f64,
+    pub pl1_tdp_w: Option<f64>,
+    pub pl1_duration_s: Option<f64>,
+    pub pl2_tdp_w: Option<f64>,
+    pub pl2_duration_s: Option<f64>,
+    pub trip_temp_c: Option<f64>,
+    pub hwp_mode: Option<bool>,
+    /// Configurable TDP level, 0 being the nominal TDP.
+    pub ctdp: Option<u8>,
+    /// Keep the bidirectional PROCHOT input of the CPU disabled.
+    pub disable_bdprochot: bool,
+    /// Voltage offset in mV per plane, from `UNDERVOLT.<source>` or `UNDERVOLT`.
+    pub undervolt: HashMap<&'static str, f64>,
+    /// Current limit in A per plane, from `ICCMAX.<source>` or `ICCMAX`.
+    pub iccmax: HashMap<&'static str, f64>,
 }
 
-fn set_icc_max() -> ! {
-    todo!()
+/// Validated contents of the config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThrottledConfig {
+    pub general: GeneralConfig,
+    pub ac: PowerProfile,
+    pub battery: PowerProfile,
 }
 
-fn set_hwp() -> ! {
-    todo!()
+impl ThrottledConfig {
+    pub fn profile(&self, source: PowerSource) -> &PowerProfile {
+        match source {
+            PowerSource::Ac => &self.ac,
+            PowerSource::Battery => &self.battery,
+        }
+    }
 }
 
-// TODO
-fn fatal(msg: &str) -> ! {
-    panic!("{}", msg);
+/// Returns the planes of `planes` ordered by their index.
+fn sorted_planes(planes: &HashMap<&'static str, u64>) -> Vec<(&'static str, u64)> {
+    let mut planes: Vec<_> = planes.iter().map(|(k, v)| (*k, *v)).collect();
+    planes.sort_by_key(|(_, v)| *v);
+    planes
 }
 
-/// Represents the information obtained from the `uname` system call.
+/// Reads and validates the config file at `path`.
 ///
-/// Corresponds to the `struct utsname` in C.
-#[derive(Debug)]
-pub struct UnameInfo {
-    pub sysname: String,     // Operating system name (e.g., "Linux", "Darwin")
-    pub nodename: String,    // Network node hostname (e.g., "my-laptop")
-    pub pub_release: String, // Operating system release (e.g., "5.15.0-76-generic")
-    pub version: String, // Operating system version (e.g., "#83~22.04.1-Ubuntu SMP PREEMPT_DYNAMIC...")
-    pub machine: String, // Hardware identifier (e.g., "x86_64", "arm64")
-    // domainname is present on some systems (like Linux) but not others (like macOS).
-    // We'll handle it conditionally.
-    pub domainname: Option<String>,
+/// Out of range values are clamped; a message for each is returned alongside the config.
+pub fn load_config(path: &Path) -> Result<(ThrottledConfig, Vec<String>), Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Unable to read {}: {e}", path.display())))?;
+    parse_config(&text)
 }
 
-/// Helper function to convert a null-terminated C char array to a Rust String.
-fn c_char_array_to_string(arr: &[c_char]) -> io::Result<String> {
-    // Safety: CStr::from_ptr expects a null-terminated string.
-    // We assume the C array is null-terminated as per uname() contract.
-    let c_str = unsafe { CStr::from_ptr(arr.as_ptr()) };
-    c_str.to_str().map(|s| s.to_owned()).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Invalid UTF-8 in uname string: {}", e),
-        )
-    })
+/// Validates a config in the throttled.conf format, see [`load_config`].
+pub fn parse_config(text: &str) -> Result<(ThrottledConfig, Vec<String>), Error> {
+    parse_ini(text).map_err(Error::Config)
 }
 
-/// Retrieves system information using the `uname` system call.
-///
-/// Returns a `Result` containing `UnameInfo` on success or an `io::Error` on failure.
-pub fn get_uname_info() -> io::Result<UnameInfo> {
-    // Create an uninitialized utsname struct.
-    // std::mem::zeroed is safe here because uname() will fully initialize it.
-    let mut info = unsafe { std::mem::zeroed::<libc::utsname>() };
+fn parse_ini(text: &str) -> Result<(ThrottledConfig, Vec<String>), String> {
+    let mut ini = Ini::new();
+    ini.read(text.to_owned())?;
+    let sections = ini.sections();
+    let has_section = |key: &str| sections.contains(&key.to_lowercase());
+    let mut warnings = Vec::new();
 
-    let result = unsafe {
-        // Call the uname system function.
-        // It takes a mutable pointer to the utsname struct.
-        libc::uname(&mut info)
+    let general = GeneralConfig {
+        enabled: ini.getboolcoerce("GENERAL", "Enabled")?.unwrap_or(true),
+        autoreload: ini.getboolcoerce("GENERAL", "Autoreload")?.unwrap_or(false),
+        mchbar_mirror: ini
+            .getboolcoerce("GENERAL", "MCHBAR_Mirror")?
+            .unwrap_or(true),
+        restore_on_exit: ini
+            .getboolcoerce("GENERAL", "Restore_On_Exit")?
+            .unwrap_or(true),
     };
 
-    if result < 0 {
-        // If uname returns a negative value, an error occurred.
-        // Get the last OS error (errno) and convert it to an io::Error.
-        return Err(io::Error::last_os_error());
-    }
+    let mut profiles = Vec::with_capacity(2);
+    for power_source in [PowerSource::Ac, PowerSource::Battery] {
+        let section = power_source.as_str();
+        let opt = |option: &str| -> Result<Option<f64>, String> {
+            match ini.getfloat(section, option)? {
+                Some(value) if !value.is_finite() => Err(format!(
+                    "\"{option}\" in [{section}] must be a finite number, got {value}."
+                )),
+                value => Ok(value.map(|value| value.max(0.001))),
+            }
+        };
+        let Some(update_rate_s) = opt("Update_Rate_s")? else {
+            return Err(format!(
+                "The mandatory \"Update_Rate_s\" parameter is missing in [{section}]."
+            ));
+        };
+        if update_rate_s > UPDATE_RATE_MAX_S {
+            return Err(format!(
+                "\"Update_Rate_s\" in [{section}] must be at most {UPDATE_RATE_MAX_S} s, got {update_rate_s}."
+            ));
+        }
+        let mut profile = PowerProfile {
+            update_rate_s,
+            pl1_tdp_w: opt("PL1_Tdp_W")?,
+            pl1_duration_s: opt("PL1_Duration_s")?,
+            pl2_tdp_w: opt("PL2_Tdp_W")?,
+            pl2_duration_s: opt("PL2_Duration_S")?,
+            trip_temp_c: None,
+            hwp_mode: ini.getboolcoerce(section, "HWP_Mode")?,
+            ctdp: None,
+            disable_bdprochot: ini
+                .getboolcoerce(section, "Disable_BDPROCHOT")?
+                .unwrap_or(false),
+            undervolt: HashMap::new(),
+            iccmax: HashMap::new(),
+        };
 
-    // Convert each C string field to a Rust String.
-    let sysname = c_char_array_to_string(&info.sysname)?;
-    let nodename = c_char_array_to_string(&info.nodename)?;
-    let release = c_char_array_to_string(&info.release)?;
-    let version = c_char_array_to_string(&info.version)?;
-    let machine = c_char_array_to_string(&info.machine)?;
+        if let Some(trip_temp) = opt("Trip_Temp_C")? {
+            let valid_trip_temp = trip_temp.clamp(TRIP_TEMP_MIN, TRIP_TEMP_MAX);
+            if valid_trip_temp != trip_temp {
+                warnings.push(format!("{section} trip temp ({trip_temp}) not in valid range: [{TRIP_TEMP_MIN}, {TRIP_TEMP_MAX}], overriding"));
+            }
+            profile.trip_temp_c = Some(valid_trip_temp);
+        }
 
-    // The 'domainname' field is platform-specific and not always present
-    // or filled. It's typically part of `utsname` on Linux, but not macOS.
-    let domainname = {
-        #[cfg(any(target_os = "linux", target_os = "android"))]
-        {
-            // On Linux/Android, utsname has a domainname field.
-            let domain_str = c_char_array_to_string(&info.domainname)?;
-            if domain_str.is_empty() {
-                None
+        if let Some(ctdp) = ini.getint(section, "cTDP")? {
+            let valid_ctdp = ctdp.clamp(0, CTDP_MAX_LEVEL.into());
+            if valid_ctdp != ctdp {
+                warnings.push(format!(
+                    "{section} cTDP ({ctdp}) not in valid range: [0, {CTDP_MAX_LEVEL}], overriding"
+                ));
+            }
+            profile.ctdp = Some(valid_ctdp as u8);
+        }
+        profiles.push(profile);
+    }
+
+    // validate undervolt settings config
+    let mut undervolt: HashMap<&str, HashMap<&'static str, f64>> = HashMap::new();
+    for key in UNDERVOLT_KEYS {
+        if !has_section(key) {
+            continue;
+        }
+        let planes = undervolt.entry(key).or_default();
+        for (plane, _) in sorted_planes(&VOLTAGE_PLANES) {
+            let val = ini.getfloat(key, plane)?.unwrap_or_default();
+            if !val.is_finite() {
+                return Err(format!(
+                    "\"{plane}\" in [{key}] must be a finite number, got {val}."
+                ));
+            }
+            let valid_val = if (val * 1.024).round() < UNDERVOLT_MIN_STEPS as f64 {
+                warnings.push(format!(
+                    "Invalid {key} {plane} value: {val} (below {:.0}), ignoring",
+                    UNDERVOLT_MIN_STEPS as f64 / 1.024
+                ));
+                0.0
             } else {
-                Some(domain_str)
+                val.min(0.0)
+            };
+            if val > 0.0 {
+                warnings.push(format!(
+                    "Invalid {key} {plane} value: {val} (is positive), overriding"
+                ));
             }
+            planes.insert(plane, valid_val);
         }
-    };
+    }
 
-    Ok(UnameInfo {
-        sysname,
+    // if only one of "UNDERVOLT.AC", "UNDERVOLT.BATTERY" is set, the other one means no undervolt
+    if has_section(UNDERVOLT_KEYS[1]) || has_section(UNDERVOLT_KEYS[2]) {
+        for key in UNDERVOLT_KEYS.iter().skip(1) {
+            let planes = undervolt.entry(key).or_default();
+            for plane in VOLTAGE_PLANES.keys() {
+                planes.entry(plane).or_insert(0.0);
+            }
+        }
+    }
+
+    if undervolt
+        .values()
+        .any(|planes| planes.get("CORE") != planes.get("CACHE"))
+    {
+        warnings.push("On Skylake and newer CPUs CORE and CACHE values should match!".to_owned());
+    }
+
+    // check for invalid values (ie. <= 0 or not encodable) in the IccMax settings
+    let mut iccmax: HashMap<&str, HashMap<&'static str, f64>> = HashMap::new();
+    for key in ICCMAX_KEYS {
+        if !has_section(key) {
+            continue;
+        }
+        let planes = iccmax.entry(key).or_default();
+        for (plane, _) in sorted_planes(&CURRENT_PLANES) {
+            let Some(value) = ini.getfloat(key, plane)? else {
+                continue;
+            };
+            if !(value > 0.0 && value <= ICCMAX_MAX_A) {
+                warnings.push(format!(
+                    "Invalid {key} {plane} value: {value:.2}. Removing from configuration!"
+                ));
+                continue;
+            }
+            planes.insert(plane, value);
+        }
+    }
+
+    // if only one of "ICCMAX.AC", "ICCMAX.BATTERY" is set, the other one means no IccMax
+    if has_section(ICCMAX_KEYS[1]) || has_section(ICCMAX_KEYS[2]) {
+        for key in ICCMAX_KEYS.iter().skip(1) {
+            iccmax.entry(key).or_default();
+        }
+    }
+
+    if iccmax.values().any(|planes| !planes.is_empty()) {
+        warnings
+            .push("Warning! Raising IccMax above design limits can damage your system!".to_owned());
+    }
+
+    for (power_source, profile) in [PowerSource::Ac, PowerSource::Battery]
+        .into_iter()
+        .zip(profiles.iter_mut())
+    {
+        let source_key = |key: &str| format!("{key}.{power_source}");
+        profile.undervolt = undervolt
+            .get(source_key(UNDERVOLT_KEYS[0]).as_str())
+            .or_else(|| undervolt.get(UNDERVOLT_KEYS[0]))
+            .cloned()
+            .unwrap_or_default();
+        profile.iccmax = iccmax
+            .get(source_key(ICCMAX_KEYS[0]).as_str())
+            .or_else(|| iccmax.get(ICCMAX_KEYS[0]))
+            .cloned()
+            .unwrap_or_default();
+    }
+
+    let battery = profiles.pop().unwrap();
+    let ac = profiles.pop().unwrap();
+    Ok((
+        ThrottledConfig {
+            general,
+            ac,
+            battery,
+        },
+        warnings,
+    ))
+}
+
+/// Lists the settings that differ between `old` and `new`, one `KEY: old -> new` line each.
+pub fn config_diff(old: &ThrottledConfig, new: &ThrottledConfig) -> Vec<String> {
+    fn show<T: std::fmt::Display>(value: Option<T>) -> String {
+        value.map_or("unset".to_owned(), |v| v.to_string())
+    }
+    let mut diff = Vec::new();
+    let mut compare = |key: String, old: String, new: String| {
+        if old != new {
+            diff.push(format!("{key}: {old} -> {new}"));
+        }
+    };
+    let (o, n) = (&old.general, &new.general);
+    compare(
+        "GENERAL.Enabled".into(),
+        o.enabled.to_string(),
+        n.enabled.to_string(),
+    );
+    compare(
+        "GENERAL.Autoreload".into(),
+        o.autoreload.to_string(),
+        n.autoreload.to_string(),
+    );
+    compare(
+        "GENERAL.MCHBAR_Mirror".into(),
+        o.mchbar_mirror.to_string(),
+        n.mchbar_mirror.to_string(),
+    );
+    compare(
+        "GENERAL.Restore_On_Exit".into(),
+        o.restore_on_exit.to_string(),
+        n.restore_on_exit.to_string(),
+    );
+    for source in [PowerSource::Ac, PowerSource::Battery] {
+        let (o, n) = (old.profile(source), new.profile(source));
+        let fields = [
+            (
+                "Update_Rate_s",
+                Some(o.update_rate_s),
+                Some(n.update_rate_s),
+            ),
+            ("PL1_Tdp_W", o.pl1_tdp_w, n.pl1_tdp_w),
+            ("PL1_Duration_s", o.pl1_duration_s, n.pl1_duration_s),
+            ("PL2_Tdp_W", o.pl2_tdp_w, n.pl2_tdp_w),
+            ("PL2_Duration_S", o.pl2_duration_s, n.pl2_duration_s),
+            ("Trip_Temp_C", o.trip_temp_c, n.trip_temp_c),
+        ];
+        for (key, o, n) in fields {
+            compare(format!("{source}.{key}"), show(o), show(n));
+        }
+        compare(
+            format!("{source}.HWP_Mode"),
+            show(o.hwp_mode),
+            show(n.hwp_mode),
+        );
+        compare(format!("{source}.cTDP"), show(o.ctdp), show(n.ctdp));
+        compare(
+            format!("{source}.Disable_BDPROCHOT"),
+            o.disable_bdprochot.to_string(),
+            n.disable_bdprochot.to_string(),
+        );
+        for (section, old_planes, new_planes, planes) in [
+            ("UNDERVOLT", &o.undervolt, &n.undervolt, &*VOLTAGE_PLANES),
+            ("ICCMAX", &o.iccmax, &n.iccmax, &*CURRENT_PLANES),
+        ] {
+            for (plane, _) in sorted_planes(planes) {
+                compare(
+                    format!("{section}.{source}.{plane}"),
+                    show(old_planes.get(plane)),
+                    show(new_planes.get(plane)),
+                );
+            }
+        }
+    }
+    diff
+}
+
+/// Register values to program for each power source, by register name.
+pub type RegValues = HashMap<PowerSource, HashMap<&'static str, u64>>;
+
+/// RAPL power and time units in W and s, from MSR_RAPL_POWER_UNIT.
+pub fn calc_rapl_units(rapl_power_unit: u64) -> (f64, f64) {
+    let power_unit = 1.0 / 2.0_f64.powi((rapl_power_unit & 0xF) as i32);
+    let time_unit = 1.0 / 2.0_f64.powi(((rapl_power_unit >> 16) & 0xF) as i32);
+    (power_unit, time_unit)
+}
+
+/// RAPL energy status unit in J, from MSR_RAPL_POWER_UNIT.
+pub fn calc_energy_unit(rapl_power_unit: u64) -> f64 {
+    1.0 / 2.0_f64.powi(((rapl_power_unit >> 8) & 0x1F) as i32)
+}
+
+/// Energy counted between two reads of a 32-bit energy status counter, which wraps around.
+pub fn calc_energy_delta(previous: u64, current: u64) -> u64 {
+    current.wrapping_sub(previous) & 0xFFFF_FFFF
+}
+
+/// Energy status registers sampled by [`EnergySampler`], by power plane.
+pub const ENERGY_PLANES: [(&str, &str); 3] = [
+    ("Package", "MSR_INTEL_PKG_ENERGY_STATUS"),
+    ("Graphics", "MSR_PP1_ENERGY_STATUS"),
+    ("DRAM", "MSR_DRAM_ENERGY_STATUS"),
+];
+
+/// Average power drawn by each power plane, from its energy status counter on CPU 0.
+///
+/// Keeps the last `window` samples, so the average can cover the last interval or the whole
+/// window. Every interval is wraparound-safe as long as it is shorter than the counter period.
+pub struct EnergySampler {
+    energy_unit: f64,
+    planes: Vec<(&'static str, &'static str)>,
+    window: usize,
+    samples: VecDeque<(Instant, Vec<u64>)>,
+}
+
+impl EnergySampler {
+    /// Creates a sampler of the planes the CPU implements, keeping at least two samples.
+    pub fn new(msr: &dyn MsrBackend, window: usize) -> Result<Self, Error> {
+        let energy_unit = calc_energy_unit(read_cpu0(msr, "MSR_RAPL_POWER_UNIT")?);
+        let planes = ENERGY_PLANES
+            .into_iter()
+            .filter(|(_, arg)| read_cpu0(msr, arg).is_ok())
+            .collect();
+        let window = window.max(2);
+        Ok(EnergySampler {
+            energy_unit,
+            planes,
+            window,
+            samples: VecDeque::with_capacity(window),
+        })
+    }
+
+    /// Names of the sampled planes.
+    pub fn planes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.planes.iter().map(|(plane, _)| *plane)
+    }
+
+    pub fn sample(&mut self, msr: &dyn MsrBackend) -> Result<(), Error> {
+        self.sample_at(msr, Instant::now())
+    }
+
+    /// Reads every plane's counter as of `at`, dropping the oldest sample if the window is full.
+    pub fn sample_at(&mut self, msr: &dyn MsrBackend, at: Instant) -> Result<(), Error> {
+        let counters = self
+            .planes
+            .iter()
+            .map(|(_, arg)| read_cpu0(msr, arg))
+            .collect::<Result<_, _>>()?;
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, counters));
+        Ok(())
+    }
+
+    /// Average power in W of `plane` between the last two samples.
+    pub fn watts(&self, plane: &str) -> Option<f64> {
+        self.average(plane, 2)
+    }
+
+    /// Average power in W of `plane` over every sample of the window.
+    pub fn average_watts(&self, plane: &str) -> Option<f64> {
+        self.average(plane, self.samples.len())
+    }
+
+    fn average(&self, plane: &str, count: usize) -> Option<f64> {
+        let index = self.planes.iter().position(|(name, _)| *name == plane)?;
+        if count < 2 || count > self.samples.len() {
+            return None;
+        }
+        let samples: Vec<_> = self.samples.range(self.samples.len() - count..).collect();
+        let elapsed = samples[count - 1]
+            .0
+            .duration_since(samples[0].0)
+            .as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        let energy: u64 = samples
+            .windows(2)
+            .map(|pair| calc_energy_delta(pair[0].1[index], pair[1].1[index]))
+            .sum();
+        Some(energy as f64 * self.energy_unit / elapsed)
+    }
+}
+
+fn read_cpu0(msr: &dyn MsrBackend, arg: &str) -> Result<u64, Error> {
+    readmsr(msr, arg, None, None, Some(0)).map(|value| value.first())
+}
+
+/// Bit 63 of MSR_PKG_POWER_LIMIT, set when the limits can't be changed anymore.
+const PKG_POWER_LIMIT_LOCK: u64 = 1 << 63;
+
+/// Encodes the PL1/PL2 limits of `profile` as a MSR_PKG_POWER_LIMIT value.
+///
+/// Limits missing from the profile keep their `current` value. Both limits
+/// are enabled with clamping allowed. Limits above what the 15 bit field encodes get the
+/// largest one.
+pub fn calc_pkg_power_limit(
+    profile: &PowerProfile,
+    current: u64,
+    power_unit: f64,
+    time_unit: f64,
+) -> u64 {
+    let power = |name: &str, watts: Option<f64>, current: u64| {
+        watts.map_or(current & 0x7FFF, |w| {
+            let max = 0x7FFF as f64 * power_unit;
+            if w > max {
+                warn!("{name} ({w} W) is above the largest encodable limit, using {max} W");
+            }
+            (w / power_unit).round().min(0x7FFF as f64) as u64
+        })
+    };
+    let window = |secs: Option<f64>, current: u64| {
+        secs.map_or(current & 0x7F, |t| {
+            let (y, z) = calc_time_window_vars(t, time_unit);
+            y | (z << 5)
+        })
+    };
+    let pl1 = power("PL1", profile.pl1_tdp_w, current);
+    let tw1 = window(profile.pl1_duration_s, current >> 17);
+    let pl2 = power("PL2", profile.pl2_tdp_w, current >> 32);
+    let tw2 = window(profile.pl2_duration_s, current >> 49);
+    pl1 | (1 << 15) | (1 << 16) | (tw1 << 17) | (pl2 << 32) | (1 << 47) | (1 << 48) | (tw2 << 49)
+}
+
+/// Bit 31 of MSR_TEMPERATURE_TARGET, set when the TCC activation offset is read-only.
+const TEMPERATURE_TARGET_LOCK: u64 = 1 << 31;
+
+/// TjMax in 'C, from MSR_TEMPERATURE_TARGET.
+pub fn calc_critical_temp(temperature_target: u64) -> f64 {
+    ((temperature_target >> 16) & 0xFF) as f64
+}
+
+/// Sets the TCC activation offset of `current` so throttling starts at `trip_temp` 'C.
+///
+/// All other bits of MSR_TEMPERATURE_TARGET are kept.
+pub fn calc_temperature_target(current: u64, trip_temp: f64) -> u64 {
+    let offset = (calc_critical_temp(current) - trip_temp).round().max(0.0) as u64 & 0x3F;
+    (current & !(0x3F << 24)) | (offset << 24)
+}
+
+/// Highest level MSR_CONFIG_TDP_CONTROL can select.
+const CTDP_MAX_LEVEL: u8 = 2;
+/// Bit 31 of MSR_CONFIG_TDP_CONTROL, set when the level can't be changed anymore.
+const CONFIG_TDP_LOCK: u64 = 1 << 31;
+
+/// Selects the configurable TDP `level` in `current`, keeping the other bits.
+pub fn calc_ctdp_control(current: u64, level: u8) -> u64 {
+    (current & !0x3) | u64::from(level & 0x3)
+}
+
+fn get_reg_values(
+    msr: &dyn MsrBackend,
+    config: &ThrottledConfig,
+    platform_info: &PlatformInfo,
+) -> Result<RegValues, Error> {
+    let read = |arg| readmsr_flat(msr, arg, None, None);
+    let (power_unit, time_unit) = calc_rapl_units(read("MSR_RAPL_POWER_UNIT")?);
+    let mut regs = RegValues::new();
+    for power_source in [PowerSource::Ac, PowerSource::Battery] {
+        let profile = config.profile(power_source);
+        let source_regs = regs.entry(power_source).or_default();
+        let skip = |e: Error, option: &str| warn!("{e}, ignoring {power_source} {option}.");
+        if profile.trip_temp_c.is_some() && !platform_info.programmable_tj_offset {
+            skip(
+                Error::Unsupported("Setting the temperature target"),
+                "Trip_Temp_C",
+            );
+        } else if let Some(trip_temp) = profile.trip_temp_c {
+            let current = read("MSR_TEMPERATURE_TARGET")?;
+            let critical_temp = calc_critical_temp(current);
+            // keep at least 3 'C from the CPU critical temperature
+            let max_trip_temp = TRIP_TEMP_MAX.min(critical_temp - 3.0);
+            if max_trip_temp < TRIP_TEMP_MIN {
+                // TjMax reads 0 on VMs and unsupported CPUs forced through
+                warn!("TjMax ({critical_temp}) leaves no trip temp above {TRIP_TEMP_MIN}, ignoring {power_source} Trip_Temp_C.");
+            } else if current & TEMPERATURE_TARGET_LOCK != 0 {
+                skip(Error::Locked("MSR_TEMPERATURE_TARGET"), "Trip_Temp_C");
+            } else {
+                let valid_trip_temp = trip_temp.clamp(TRIP_TEMP_MIN, max_trip_temp);
+                if valid_trip_temp != trip_temp {
+                    warn!("{power_source} trip temp ({trip_temp}) too close to TjMax ({critical_temp}), using {valid_trip_temp}");
+                }
+                source_regs.insert(
+                    "MSR_TEMPERATURE_TARGET",
+                    calc_temperature_target(current, valid_trip_temp),
+                );
+            }
+        }
+        if let Some(level) = profile.ctdp {
+            if platform_info.config_tdp_levels == 0 {
+                skip(Error::Unsupported("cTDP"), "cTDP");
+            } else if level > platform_info.config_tdp_levels {
+                warn!(
+                    "The {power_source} cTDP level {level} is not supported by this CPU (max {}).",
+                    platform_info.config_tdp_levels
+                );
+            } else {
+                match read("MSR_CONFIG_TDP_CONTROL") {
+                    Ok(current) if current & CONFIG_TDP_LOCK != 0 => {
+                        skip(Error::Locked("MSR_CONFIG_TDP_CONTROL"), "cTDP")
+                    }
+                    Ok(current) => {
+                        source_regs
+                            .insert("MSR_CONFIG_TDP_CONTROL", calc_ctdp_control(current, level));
+                    }
+                    Err(e) => skip(e, "cTDP"),
+                }
+            }
+        }
+        let limits = [
+            profile.pl1_tdp_w,
+            profile.pl1_duration_s,
+            profile.pl2_tdp_w,
+            profile.pl2_duration_s,
+        ];
+        if limits.iter().any(Option::is_some) {
+            let current = read("MSR_PKG_POWER_LIMIT")?;
+            if current & PKG_POWER_LIMIT_LOCK != 0 {
+                skip(Error::Locked("MSR_PKG_POWER_LIMIT"), "power limits");
+                continue;
+            }
+            source_regs.insert(
+                "MSR_PKG_POWER_LIMIT",
+                calc_pkg_power_limit(profile, current, power_unit, time_unit),
+            );
+        }
+    }
+    Ok(regs)
+}
+
+/// Returns the first value of `arg` that differs from `value` across CPUs, if any.
+fn msr_drift(msr: &dyn MsrBackend, arg: &str, value: u64) -> Result<Option<u64>, Error> {
+    let (register, addr) = msr_addr(arg)?;
+    for cpu in register_cpus(msr, arg)? {
+        let current = msr
+            .read(cpu, addr)
+            .map_err(|source| Error::Read { register, source })?;
+        if current != value {
+            return Ok(Some(current));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the register values of `regs` that drifted from their target, returning how many were
+/// written.
+pub fn apply_regs(msr: &dyn MsrBackend, regs: &HashMap<&'static str, u64>) -> usize {
+    let mut regs: Vec<_> = regs.iter().collect();
+    regs.sort();
+    let mut written = 0;
+    for (arg, value) in regs {
+        match msr_drift(msr, arg, *value) {
+            Ok(None) => continue,
+            Ok(Some(current)) => debug!("{arg} drifted to {current:#x}, target {value:#x}"),
+            // Try the write anyway, it reports its own errors.
+            Err(e) => debug!("{e}"),
+        }
+        match writemsr(msr, arg, *value) {
+            Ok(()) => {
+                debug!("{arg} set to {value:#x}");
+                written += 1;
+            }
+            Err(e) => warn!("Unable to set {arg}: {e}"),
+        }
+    }
+    written
+}
+
+/// A validated config with the register values of each of its profiles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub config: ThrottledConfig,
+    pub regs: RegValues,
+}
+
+impl Settings {
+    /// Loads the config at `path` and computes its register values, returning the config
+    /// warnings along.
+    pub fn load(
+        msr: &dyn MsrBackend,
+        path: &Path,
+        platform_info: &PlatformInfo,
+    ) -> Result<(Self, Vec<String>), Error> {
+        let (config, warnings) = load_config(path)?;
+        let regs = get_reg_values(msr, &config, platform_info)?;
+        Ok((Settings { config, regs }, warnings))
+    }
+
+    /// Switches to the config at `path`, returning what changed as given by [`config_diff`].
+    ///
+    /// Nothing changes unless the whole config is valid. Config warnings are logged.
+    pub fn reload(
+        &mut self,
+        msr: &dyn MsrBackend,
+        path: &Path,
+        platform_info: &PlatformInfo,
+    ) -> Result<Vec<String>, Error> {
+        let (settings, warnings) = Settings::load(msr, path, platform_info)?;
+        for warning in warnings {
+            warn!("{warning}");
+        }
+        let diff = config_diff(&self.config, &settings.config);
+        *self = settings;
+        Ok(diff)
+    }
+}
+
+pub fn get_undervolt(
+    msr: &dyn MsrBackend,
+    unsupported_features: &Vec<&'static str>,
+    plane: Option<&'static str>,
+    convert: bool,
+    test_msr: Arc<Mutex<bool>>,
+) -> Result<HashMap<&'static str, i64>, Error> {
+    if unsupported_features.contains(&"UNDERVOLT") {
+        return Err(Error::Unsupported("Undervolt"));
+    }
+    let mut out = HashMap::new();
+    let planes = match plane {
+        Some(x) => VOLTAGE_PLANES
+            .get_key_value(x)
+            .map(|(k, v)| HashMap::from([(*k, *v)]))
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("plane {x} not found in VOLTAGE_PLANES"))
+            })?,
+        None => VOLTAGE_PLANES.clone(),
+    };
+    for (k, v) in planes {
+        write_register(msr, "MSR_OC_MAILBOX", 0x8000001000000000 | (v << 40))?;
+        let read_result = readmsr_flat(msr, "MSR_OC_MAILBOX", None, None);
+        let read_value = match read_result {
+            Ok(value) => value & 0xFFFFFFFF,
+            // While probing, a refused read only means undervolt is unsupported.
+            Err(Error::Read { register, source })
+                if source.kind() == io::ErrorKind::PermissionDenied
+                    && !test_msr.lock().is_ok_and(|x| *x) =>
+            {
+                return Err(Error::PermissionDenied { register, source });
+            }
+            Err(e) => return Err(e),
+        };
+        let val = if convert {
+            calc_undervolt_mv(read_value)
+        } else {
+            read_value as i64
+        };
+        out.insert(k, val);
+    }
+    Ok(out)
+}
+
+/// Encodes a MSR_OC_MAILBOX command setting the voltage offset of `plane` to `offset_mv`.
+pub fn calc_undervolt_msr(plane: &str, offset_mv: f64) -> Result<u64, Error> {
+    let plane_idx = *VOLTAGE_PLANES.get(plane).ok_or_else(|| {
+        Error::InvalidArgument(format!("plane {plane} not found in VOLTAGE_PLANES"))
+    })?;
+    let offset = (offset_mv * 1.024).round();
+    if !(UNDERVOLT_MIN_STEPS as f64..=0.0).contains(&offset) {
+        return Err(Error::InvalidArgument(format!(
+            "{plane} offset {offset_mv} mV not in encodable range: [{:.0}, 0]",
+            UNDERVOLT_MIN_STEPS as f64 / 1.024
+        )));
+    }
+    let offset = 0xFFE00000 & (((offset as i64 as u64) & 0xFFF) << 21);
+    Ok(0x8000001100000000 | (plane_idx << 40) | offset)
+}
+
+/// Writes the voltage offset of every plane in `undervolt`, then reads it back.
+///
+/// Returns for each plane, by plane index, the offset in mV read back, or why it failed.
+pub fn set_undervolt(
+    msr: &dyn MsrBackend,
+    unsupported_features: &Vec<&'static str>,
+    undervolt: &HashMap<&'static str, f64>,
+) -> Vec<(&'static str, Result<i64, Error>)> {
+    let mut planes: Vec<_> = undervolt.iter().map(|(k, v)| (*k, *v)).collect();
+    planes.sort_by_key(|(plane, _)| VOLTAGE_PLANES.get(plane));
+    let test_msr = Arc::new(Mutex::new(false));
+    planes
+        .into_iter()
+        .map(|(plane, offset_mv)| {
+            let apply = || {
+                let write_value = calc_undervolt_msr(plane, offset_mv)?;
+                write_register(msr, "MSR_OC_MAILBOX", write_value)?;
+                let read = get_undervolt(
+                    msr,
+                    unsupported_features,
+                    Some(plane),
+                    false,
+                    test_msr.clone(),
+                )?;
+                let read_value = read[plane] as u64;
+                if read_value & 0xFFE00000 == write_value & 0xFFE00000 {
+                    Ok(calc_undervolt_mv(read_value))
+                } else {
+                    Err(Error::NotApplied(format!(
+                        "{plane} offset did not take: wrote {:#x}, read back {read_value:#x}",
+                        write_value & 0xFFFFFFFF
+                    )))
+                }
+            };
+            (plane, apply())
+        })
+        .collect()
+}
+
+pub fn calc_undervolt_mv(read_value: u64) -> i64 {
+    let offset = ((read_value & 0xFFE00000) >> 21) as i32;
+    let res: i32 = if offset <= 0x400 {
+        offset
+    } else {
+        -(0x800 - offset)
+    };
+    ((res as f64) / 1.024).round() as i64
+}
+
+/// Where a cpu sits in the machine, from `/sys/devices/system/cpu/cpuN/topology`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuTopology {
+    pub package: usize,
+    /// Core id, unique within the package.
+    pub core: usize,
+}
+
+impl CpuTopology {
+    /// Topology of a cpu assumed to share nothing with the others.
+    ///
+    /// Its package id counts down from `usize::MAX` so it can't be one a real package has.
+    fn unshared(cpu: usize) -> Self {
+        CpuTopology {
+            package: usize::MAX - cpu,
+            core: 0,
+        }
+    }
+}
+
+/// Where the kernel lists the cpus.
+const CPU_SYSFS: &str = "/sys/devices/system/cpu";
+
+/// Parses a cpu list the way sysfs formats it, e.g. `0-3,6`. Inverse of [`format_cpu_list`].
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, Error> {
+    let invalid = || Error::InvalidArgument(format!("invalid cpu list {list:?}"));
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start: usize = start.parse().map_err(|_| invalid())?;
+        let end: usize = end.parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// The online cpus, as listed by sysfs.
+fn online_cpus() -> Result<Vec<usize>, Error> {
+    let path = Path::new(CPU_SYSFS).join("online");
+    let list = std::fs::read_to_string(&path)
+        .map_err(|e| Error::Kernel(format!("Unable to read {}: {e}", path.display())))?;
+    parse_cpu_list(&list)
+}
+
+/// Reads the topology of `cpu` from sysfs.
+fn read_topology(cpu: usize) -> io::Result<CpuTopology> {
+    let dir = Path::new(CPU_SYSFS).join(format!("cpu{cpu}/topology"));
+    let read_id = |name: &str| -> io::Result<usize> {
+        std::fs::read_to_string(dir.join(name))?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    Ok(CpuTopology {
+        package: read_id("physical_package_id")?,
+        core: read_id("core_id")?,
+    })
+}
+
+/// The cpus to access a register of `scope` on: the first cpu of each thread, core or package.
+pub fn scope_cpus(msr: &dyn MsrBackend, scope: MsrScope) -> Vec<usize> {
+    let mut seen = HashSet::new();
+    msr.cpus()
+        .into_iter()
+        .filter(|&cpu| {
+            let topology = msr.topology(cpu);
+            match scope {
+                MsrScope::Thread => true,
+                MsrScope::Core => seen.insert(topology),
+                MsrScope::Package => seen.insert(CpuTopology {
+                    core: 0,
+                    ..topology
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Access to the model specific registers of every cpu.
+pub trait MsrBackend {
+    /// The cpus whose registers can be accessed, the online ones.
+    fn cpus(&self) -> Vec<usize>;
+    /// Package and core of `cpu`.
+    ///
+    /// Defaults to every cpu being alone in its package, so registers of any scope are accessed
+    /// on each of them.
+    fn topology(&self, cpu: usize) -> CpuTopology {
+        CpuTopology::unshared(cpu)
+    }
+    /// Reads register `addr` of `cpu`.
+    fn read(&self, cpu: usize, addr: u64) -> io::Result<u64>;
+    /// Writes `value` to register `addr` of `cpu`.
+    fn write(&self, cpu: usize, addr: u64, value: u64) -> io::Result<()>;
+}
+
+/// The real backend, going through the msr driver nodes at `<root>/<cpu>/msr`.
+pub struct DevMsr {
+    root: PathBuf,
+    /// Fixed number of cpus, or `None` to follow the online cpus and their topology in sysfs.
+    cpus: Option<usize>,
+    /// Topology read from sysfs so far. Offline cpus have none to read.
+    topology: RefCell<HashMap<usize, CpuTopology>>,
+}
+
+impl DevMsr {
+    /// Uses `/dev/cpu`, loading the msr module if needed.
+    pub fn new() -> Result<Self, Error> {
+        if !Path::new("/dev/cpu/0/msr").exists() {
+            let is_msr_loaded = Command::new("modprobe")
+                .arg("msr")
+                .status()
+                .is_ok_and(|exit| exit.success());
+            if !is_msr_loaded {
+                return Err(Error::MsrModule);
+            }
+        }
+        Ok(DevMsr {
+            root: PathBuf::from("/dev/cpu"),
+            cpus: None,
+            topology: RefCell::default(),
+        })
+    }
+
+    /// Uses `cpus` msr files laid out like `/dev/cpu` under `root`, each cpu in its own package.
+    pub fn with_root(root: impl Into<PathBuf>, cpus: usize) -> Self {
+        DevMsr {
+            root: root.into(),
+            cpus: Some(cpus),
+            topology: RefCell::default(),
+        }
+    }
+
+    fn path(&self, cpu: usize) -> PathBuf {
+        self.root.join(cpu.to_string()).join("msr")
+    }
+}
+
+impl MsrBackend for DevMsr {
+    fn cpus(&self) -> Vec<usize> {
+        if let Some(cpus) = self.cpus {
+            return (0..cpus).collect();
+        }
+        online_cpus().unwrap_or_else(|e| {
+            debug!(
+                "{e}, assuming the first {} cpus are online",
+                num_cpus::get()
+            );
+            (0..num_cpus::get()).collect()
+        })
+    }
+
+    fn topology(&self, cpu: usize) -> CpuTopology {
+        if self.cpus.is_some() {
+            return CpuTopology::unshared(cpu);
+        }
+        if let Some(topology) = self.topology.borrow().get(&cpu) {
+            return *topology;
+        }
+        match read_topology(cpu) {
+            Ok(topology) => {
+                self.topology.borrow_mut().insert(cpu, topology);
+                topology
+            }
+            Err(e) => {
+                debug!("Unable to read the topology of cpu {cpu}: {e}");
+                CpuTopology::unshared(cpu)
+            }
+        }
+    }
+
+    fn read(&self, cpu: usize, addr: u64) -> io::Result<u64> {
+        let mut fh = File::open(self.path(cpu))?;
+        let mut buffer: [u8; 8] = [0; 8];
+        fh.seek(io::SeekFrom::Start(addr))?;
+        fh.read_exact(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    }
+
+    fn write(&self, cpu: usize, addr: u64, value: u64) -> io::Result<()> {
+        let mut fh = File::options().write(true).open(self.path(cpu))?;
+        fh.seek(io::SeekFrom::Start(addr))?;
+        fh.write_all(&value.to_le_bytes())
+    }
+}
+
+/// In-memory backend for tests.
+///
+/// Registers that were never set fail to read, like unimplemented MSRs do.
+/// MSR_OC_MAILBOX behaves like the real mailbox: write commands store the
+/// voltage offset / current limit of a plane, read commands return it.
+/// Each cpu is in its own package unless placed with [`FakeMsr::set_topology`].
+/// Offline cpus can't be accessed at all.
+#[derive(Debug, Default)]
+pub struct FakeMsr {
+    cpus: usize,
+    topology: RefCell<HashMap<usize, CpuTopology>>,
+    offline: RefCell<HashSet<usize>>,
+    regs: RefCell<HashMap<(usize, u64), u64>>,
+    mailbox: RefCell<HashMap<(usize, u64, u64), u64>>,
+    locked: RefCell<Vec<(usize, u64)>>,
+}
+
+impl FakeMsr {
+    pub fn new(cpus: usize) -> Self {
+        FakeMsr {
+            cpus,
+            ..Default::default()
+        }
+    }
+
+    /// Sets register `addr` to `value` on every cpu.
+    pub fn set(&self, addr: u64, value: u64) {
+        for cpu in 0..self.cpus {
+            self.regs.borrow_mut().insert((cpu, addr), value);
+        }
+    }
+
+    /// Returns the current value of register `addr` on `cpu`.
+    pub fn get(&self, cpu: usize, addr: u64) -> Option<u64> {
+        self.regs.borrow().get(&(cpu, addr)).copied()
+    }
+
+    /// Makes writes to register `addr` of `cpu` silently ignored, like a locked register.
+    pub fn lock(&self, cpu: usize, addr: u64) {
+        self.locked.borrow_mut().push((cpu, addr));
+    }
+
+    /// Places `cpu` on core `core` of package `package`.
+    pub fn set_topology(&self, cpu: usize, package: usize, core: usize) {
+        self.topology
+            .borrow_mut()
+            .insert(cpu, CpuTopology { package, core });
+    }
+
+    /// Takes `cpu` offline or brings it back online.
+    pub fn set_online(&self, cpu: usize, online: bool) {
+        if online {
+            self.offline.borrow_mut().remove(&cpu);
+        } else {
+            self.offline.borrow_mut().insert(cpu);
+        }
+    }
+}
+
+impl MsrBackend for FakeMsr {
+    fn cpus(&self) -> Vec<usize> {
+        let offline = self.offline.borrow();
+        (0..self.cpus)
+            .filter(|cpu| !offline.contains(cpu))
+            .collect()
+    }
+
+    fn topology(&self, cpu: usize) -> CpuTopology {
+        self.topology
+            .borrow()
+            .get(&cpu)
+            .copied()
+            .unwrap_or(CpuTopology::unshared(cpu))
+    }
+
+    fn read(&self, cpu: usize, addr: u64) -> io::Result<u64> {
+        if self.offline.borrow().contains(&cpu) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.get(cpu, addr)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EIO))
+    }
+
+    fn write(&self, cpu: usize, addr: u64, value: u64) -> io::Result<()> {
+        if cpu >= self.cpus || self.offline.borrow().contains(&cpu) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if self.locked.borrow().contains(&(cpu, addr)) {
+            return Ok(());
+        }
+        let mut stored = value;
+        if addr == MSR_DICT["MSR_OC_MAILBOX"].0 && value >> 63 == 1 {
+            let command = (value >> 32) & 0xFF;
+            let plane = (value >> 40) & 0x7;
+            let mut mailbox = self.mailbox.borrow_mut();
+            // odd commands write the value later returned by the preceding even one
+            if command & 1 == 1 {
+                mailbox.insert((cpu, command - 1, plane), value & 0xFFFFFFFF);
+            }
+            stored = mailbox
+                .get(&(cpu, command & !1, plane))
+                .copied()
+                .unwrap_or_default();
+        }
+        self.regs.borrow_mut().insert((cpu, addr), stored);
+        Ok(())
+    }
+}
+
+/// Backend passing reads through to `inner` but keeping writes to itself.
+///
+/// Later reads see the planned values. No MSR_OC_MAILBOX command reaches `inner`: write commands
+/// are kept, read commands are answered from them, and fail for planes no write was planned for.
+pub struct DryRunMsr<M: MsrBackend> {
+    inner: M,
+    regs: RefCell<HashMap<(usize, u64), u64>>,
+    /// Planned mailbox data by read command and plane.
+    mailbox: RefCell<HashMap<(u64, u64), u64>>,
+    /// Reply to the last mailbox command, `None` before the first one. A read command for a
+    /// plane without a planned write gets no reply.
+    mailbox_reply: Cell<Option<Option<u64>>>,
+}
+
+impl<M: MsrBackend> DryRunMsr<M> {
+    pub fn new(inner: M) -> Self {
+        DryRunMsr {
+            inner,
+            regs: RefCell::default(),
+            mailbox: RefCell::default(),
+            mailbox_reply: Cell::new(None),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Planned writes as `(address, value)`, by address. Mailbox writes are listed as the
+    /// command that would have been sent, one per command and plane.
+    pub fn planned(&self) -> Vec<(u64, u64)> {
+        let mailbox_addr = MSR_DICT["MSR_OC_MAILBOX"].0;
+        let mut planned: Vec<_> = self
+            .regs
+            .borrow()
+            .iter()
+            .filter(|((cpu, _), _)| *cpu == 0)
+            .map(|((_, addr), value)| (*addr, *value))
+            .collect();
+        planned.extend(
+            self.mailbox
+                .borrow()
+                .iter()
+                .map(|((command, plane), data)| {
+                    (
+                        mailbox_addr,
+                        1 << 63 | (command | 1) << 32 | plane << 40 | data,
+                    )
+                }),
+        );
+        planned.sort();
+        planned
+    }
+}
+
+impl<M: MsrBackend> MsrBackend for DryRunMsr<M> {
+    fn cpus(&self) -> Vec<usize> {
+        self.inner.cpus()
+    }
+
+    fn topology(&self, cpu: usize) -> CpuTopology {
+        self.inner.topology(cpu)
+    }
+
+    fn read(&self, cpu: usize, addr: u64) -> io::Result<u64> {
+        if addr == MSR_DICT["MSR_OC_MAILBOX"].0 {
+            match self.mailbox_reply.get() {
+                Some(Some(reply)) => return Ok(reply),
+                Some(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "the OC mailbox is not queried in a dry run",
+                    ))
+                }
+                None => {}
+            }
+        }
+        match self.regs.borrow().get(&(cpu, addr)) {
+            Some(value) => Ok(*value),
+            None => self.inner.read(cpu, addr),
+        }
+    }
+
+    fn write(&self, cpu: usize, addr: u64, value: u64) -> io::Result<()> {
+        if addr != MSR_DICT["MSR_OC_MAILBOX"].0 || value >> 63 == 0 {
+            self.regs.borrow_mut().insert((cpu, addr), value);
+            return Ok(());
+        }
+        let command = (value >> 32) & 0xFF;
+        let plane = (value >> 40) & 0x7;
+        if command & 1 == 1 {
+            let data = value & 0xFFFFFFFF;
+            self.mailbox.borrow_mut().insert((command - 1, plane), data);
+            self.mailbox_reply.set(Some(Some(data)));
+            return Ok(());
+        }
+        let planned = self.mailbox.borrow().get(&(command, plane)).copied();
+        self.mailbox_reply.set(Some(planned));
+        Ok(())
+    }
+}
+
+impl<M: MsrBackend + ?Sized> MsrBackend for Rc<M> {
+    fn cpus(&self) -> Vec<usize> {
+        self.as_ref().cpus()
+    }
+
+    fn topology(&self, cpu: usize) -> CpuTopology {
+        self.as_ref().topology(cpu)
+    }
+
+    fn read(&self, cpu: usize, addr: u64) -> io::Result<u64> {
+        self.as_ref().read(cpu, addr)
+    }
+
+    fn write(&self, cpu: usize, addr: u64, value: u64) -> io::Result<()> {
+        self.as_ref().write(cpu, addr, value)
+    }
+}
+
+/// Value returned by [`readmsr`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MsrValue {
+    /// Every targeted cpu returned this value.
+    Uniform(u64),
+    /// The cpus disagree, value of each as `(cpu, value)`.
+    PerCpu(Vec<(usize, u64)>),
+}
+
+impl MsrValue {
+    /// Value of the first targeted cpu.
+    pub fn first(&self) -> u64 {
+        match self {
+            MsrValue::Uniform(value) => *value,
+            MsrValue::PerCpu(values) => values[0].1,
+        }
+    }
+}
+
+/// Reads bits `from..=to` (default the whole register) of `arg` on `cpu`, or on every cpu.
+///
+/// Without a `cpu`, registers shared by several cpus are read once per core or package.
+pub fn readmsr(
+    msr: &dyn MsrBackend,
+    arg: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+    cpu: Option<usize>,
+) -> Result<MsrValue, Error> {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(63);
+    if from > to || to > 63 {
+        return Err(Error::InvalidArgument(format!(
+            "invalid bit range {from}..={to}"
+        )));
+    }
+    let cpus = match cpu {
+        Some(cpu) if !msr.cpus().contains(&cpu) => {
+            return Err(Error::InvalidArgument(format!(
+                "cpu {cpu} doesn't exist or is offline"
+            )))
+        }
+        Some(cpu) => vec![cpu],
+        None => register_cpus(msr, arg)?,
+    };
+    let (register, arg_addr) = msr_addr(arg)?;
+    let mask = u64::MAX >> (63 - (to - from));
+    let mut msr_values = Vec::with_capacity(cpus.len());
+    for cpu in cpus {
+        let value = msr
+            .read(cpu, arg_addr)
+            .map_err(|source| Error::Read { register, source })?;
+        msr_values.push((cpu, (value >> from) & mask));
+    }
+    match msr_values.as_slice() {
+        [(_, head), tail @ ..] if tail.iter().all(|(_, x)| x == head) => {
+            Ok(MsrValue::Uniform(*head))
+        }
+        [_, ..] => Ok(MsrValue::PerCpu(msr_values)),
+        [] => Err(Error::Read {
+            register,
+            source: io::Error::new(io::ErrorKind::NotFound, "No msr values found"),
+        }),
+    }
+}
+
+/// [`readmsr`] on every cpu, for registers that should hold the same value on all of them.
+///
+/// Warns if they don't and returns the value of cpu 0.
+pub fn readmsr_flat(
+    msr: &dyn MsrBackend,
+    arg: &str,
+    from: Option<usize>,
+    to: Option<usize>,
+) -> Result<u64, Error> {
+    let value = readmsr(msr, arg, from, to, None)?;
+    if let MsrValue::PerCpu(_) = value {
+        let (_, arg_addr) = msr_addr(arg)?;
+        warn!(
+            "multiple values for {} ({:x}) found. This should never happen.",
+            arg, arg_addr
+        );
+    }
+    Ok(value.first())
+}
+
+/// Registers whose read-back value is not expected to match the written one.
+///
+/// Writing the OC mailbox issues a command, reading it returns the response.
+const UNVERIFIED_MSRS: [&str; 1] = ["MSR_OC_MAILBOX"];
+
+/// Failure of a [`writemsr`] call. All cpus are rolled back before it is returned.
+#[derive(Debug)]
+pub enum MsrWriteError {
+    /// No register has that name.
+    UnknownRegister(String),
+    /// The msr of `cpu` could not be read or written.
+    Io { cpu: usize, source: io::Error },
+    /// `cpu` accepted the write, but reading it back returned another value.
+    Verify {
+        cpu: usize,
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl std::fmt::Display for MsrWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MsrWriteError::UnknownRegister(arg) => write!(f, "unknown register {arg}"),
+            MsrWriteError::Io { cpu, source } => {
+                write!(f, "msr access on cpu {cpu} failed: {source}")
+            }
+            MsrWriteError::Verify {
+                cpu,
+                expected,
+                found,
+            } => write!(
+                f,
+                "msr write on cpu {cpu} did not stick: wrote {expected:#x}, read back {found:#x}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MsrWriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MsrWriteError::Io { source, .. } => Some(source),
+            MsrWriteError::UnknownRegister(_) | MsrWriteError::Verify { .. } => None,
+        }
+    }
+}
+
+/// Writes `value` to the register `arg` on every cpu, once per core or package for registers
+/// they share.
+///
+/// Each cpu is read back to check the write stuck (except for [`UNVERIFIED_MSRS`]).
+/// If any cpu fails, every cpu touched so far is restored to its previous value. [`UNVERIFIED_MSRS`]
+/// aren't read nor restored: their previous value is a response, writing it would send it as a
+/// command.
+pub fn writemsr(msr: &dyn MsrBackend, arg: &str, value: u64) -> Result<(), MsrWriteError> {
+    let (arg_addr, scope) = *MSR_DICT
+        .get(arg)
+        .ok_or_else(|| MsrWriteError::UnknownRegister(arg.to_owned()))?;
+    let verify = !UNVERIFIED_MSRS.contains(&arg);
+    let mut written: Vec<(usize, u64)> = Vec::new();
+    let mut result = Ok(());
+    for cpu in scope_cpus(msr, scope) {
+        if !verify {
+            if let Err(source) = msr.write(cpu, arg_addr, value) {
+                result = Err(MsrWriteError::Io { cpu, source });
+                break;
+            }
+            continue;
+        }
+        let previous = match msr.read(cpu, arg_addr) {
+            Ok(previous) => previous,
+            Err(source) => {
+                result = Err(MsrWriteError::Io { cpu, source });
+                break;
+            }
+        };
+        written.push((cpu, previous));
+        if let Err(source) = msr.write(cpu, arg_addr, value) {
+            result = Err(MsrWriteError::Io { cpu, source });
+            break;
+        }
+        match msr.read(cpu, arg_addr) {
+            Ok(found) if found == value => {}
+            Ok(found) => {
+                result = Err(MsrWriteError::Verify {
+                    cpu,
+                    expected: value,
+                    found,
+                });
+                break;
+            }
+            Err(source) => {
+                result = Err(MsrWriteError::Io { cpu, source });
+                break;
+            }
+        }
+    }
+    if result.is_err() && verify {
+        for (cpu, previous) in written {
+            if let Err(e) = msr.write(cpu, arg_addr, previous) {
+                warn!("Unable to restore {arg} ({arg_addr:x}) on cpu {cpu}: {e}");
+            }
+        }
+    }
+    result
+}
+
+/// [`writemsr`], with the register named in the error.
+fn write_register(msr: &dyn MsrBackend, arg: &str, value: u64) -> Result<(), Error> {
+    let (register, _) = msr_addr(arg)?;
+    writemsr(msr, arg, value).map_err(|source| Error::Write { register, source })
+}
+
+/// Clears bit 0 of MSR_POWER_CTL, which lets the platform assert PROCHOT on the CPU, on every
+/// core. The other bits of each core are kept.
+///
+/// Returns the cpus it was set on.
+pub fn set_disable_bdprochot(msr: &dyn MsrBackend) -> Result<Vec<usize>, Error> {
+    let (register, addr) = msr_addr("MSR_POWER_CTL")?;
+    let mut was_set = Vec::new();
+    for cpu in register_cpus(msr, register)? {
+        let cur_val = msr
+            .read(cpu, addr)
+            .map_err(|source| Error::Read { register, source })?;
+        if cur_val & 1 == 0 {
+            continue;
+        }
+        let new_val = cur_val & 0xFFFFFFFFFFFFFFFE;
+        let source = match msr
+            .write(cpu, addr, new_val)
+            .and_then(|()| msr.read(cpu, addr))
+        {
+            Ok(found) if found == new_val => None,
+            Ok(found) => Some(MsrWriteError::Verify {
+                cpu,
+                expected: new_val,
+                found,
+            }),
+            Err(source) => Some(MsrWriteError::Io { cpu, source }),
+        };
+        if let Some(source) = source {
+            return Err(Error::Write { register, source });
+        }
+        was_set.push(cpu);
+    }
+    Ok(was_set)
+}
+
+/// MCHBAR base address used when it can't be read from the host bridge.
+const MCHBAR_BASE_DEFAULT: u64 = 0xFED10000;
+/// Offset of the package power limit mirror within MCHBAR.
+const MCHBAR_PKG_POWER_LIMIT: u64 = 0x59A0;
+const HOST_BRIDGE_CONFIG: &str = "/sys/bus/pci/devices/0000:00:00.0/config";
+
+/// A window of physical memory mapped through /dev/mem.
+pub struct Mmio {
+    map: *mut libc::c_void,
+    map_len: usize,
+    /// Offset of the requested address within the mapping.
+    start: usize,
+    len: usize,
+}
+
+impl Mmio {
+    /// Maps `len` bytes of physical memory at `addr`.
+    pub fn new(addr: u64, len: usize) -> io::Result<Self> {
+        Mmio::map(Path::new("/dev/mem"), addr, len)
+    }
+
+    /// Maps `len` bytes at offset `addr` of the file at `path`.
+    pub fn map(path: &Path, addr: u64, len: usize) -> io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let page = addr & !(page_size - 1);
+        let start = (addr - page) as usize;
+        let map_len = (start + len).next_multiple_of(page_size as usize);
+        let mem = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_SYNC)
+            .open(path)?;
+        // Safety: mapping a fresh region, the kernel checks access to the physical range.
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                mem.as_raw_fd(),
+                page as libc::off_t,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmio {
+            map,
+            map_len,
+            start,
+            len,
+        })
+    }
+
+    fn reg(&self, offset: usize) -> io::Result<*mut u32> {
+        if offset + 4 > self.len || offset & 3 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {offset:#x} is outside the {} byte window", self.len),
+            ));
+        }
+        // Safety: in bounds of the mapping, as checked above.
+        Ok(unsafe { self.map.cast::<u8>().add(self.start + offset).cast() })
+    }
+
+    pub fn read32(&self, offset: usize) -> io::Result<u32> {
+        // Safety: `reg` points into our mapping.
+        Ok(unsafe { self.reg(offset)?.read_volatile() })
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) -> io::Result<()> {
+        // Safety: `reg` points into our mapping.
+        unsafe { self.reg(offset)?.write_volatile(value) };
+        Ok(())
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+/// Reads the MCHBAR base address from the host bridge config space (offset 0x48).
+fn get_mchbar_base() -> u64 {
+    let mut buffer = [0u8; 8];
+    let base = File::open(HOST_BRIDGE_CONFIG).and_then(|mut fh| {
+        fh.seek(io::SeekFrom::Start(0x48))?;
+        fh.read_exact(&mut buffer)?;
+        Ok(u64::from_le_bytes(buffer))
+    });
+    match base {
+        // bit 0 is the enable bit, the base is 32 KiB aligned
+        Ok(base) if base & 1 == 1 => base & 0x7F_FFFF_8000,
+        Ok(_) => {
+            warn!("MCHBAR is disabled in the host bridge, assuming {MCHBAR_BASE_DEFAULT:#x}");
+            MCHBAR_BASE_DEFAULT
+        }
+        Err(e) => {
+            warn!("Unable to read the MCHBAR base ({e}), assuming {MCHBAR_BASE_DEFAULT:#x}");
+            MCHBAR_BASE_DEFAULT
+        }
+    }
+}
+
+/// Maps the MCHBAR mirror of MSR_PKG_POWER_LIMIT, which the embedded controller enforces.
+fn map_mchbar_power_limit() -> Option<Mmio> {
+    let addr = get_mchbar_base() + MCHBAR_PKG_POWER_LIMIT;
+    match Mmio::new(addr, 8) {
+        Ok(mmio) => Some(mmio),
+        Err(e) => {
+            warn!("Unable to map MCHBAR at {addr:#x} through /dev/mem, only the MSR power limit will be set: {e}");
+            None
+        }
+    }
+}
+
+/// Reads the MSR_PKG_POWER_LIMIT value held by its MCHBAR mirror.
+pub fn read_mchbar_power_limit(mchbar: &Mmio) -> io::Result<u64> {
+    Ok(mchbar.read32(0)? as u64 | (mchbar.read32(4)? as u64) << 32)
+}
+
+/// Writes a MSR_PKG_POWER_LIMIT value to its MCHBAR mirror.
+pub fn write_mchbar_power_limit(mchbar: &Mmio, value: u64) -> io::Result<()> {
+    mchbar.write32(0, (value & 0xFFFFFFFF) as u32)?;
+    mchbar.write32(4, (value >> 32) as u32)?;
+    debug!("MCHBAR PKG_POWER_LIMIT set to {value:#x}");
+    Ok(())
+}
+
+/// Reads the current limit in A of `plane`, or of every plane, through MSR_OC_MAILBOX.
+pub fn get_icc_max(
+    msr: &dyn MsrBackend,
+    plane: Option<&'static str>,
+) -> Result<HashMap<&'static str, f64>, Error> {
+    let planes = match plane {
+        Some(plane) => CURRENT_PLANES
+            .get_key_value(plane)
+            .map(|(k, v)| HashMap::from([(*k, *v)]))
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!("plane {plane} not found in CURRENT_PLANES"))
+            })?,
+        None => CURRENT_PLANES.clone(),
+    };
+    let mut out = HashMap::new();
+    for (k, v) in planes {
+        write_register(msr, "MSR_OC_MAILBOX", 0x8000001600000000 | (v << 40))?;
+        let read_value = readmsr_flat(msr, "MSR_OC_MAILBOX", None, None)? & 0x3FF;
+        out.insert(k, read_value as f64 / 4.0);
+    }
+    Ok(out)
+}
+
+/// Writes the current limit of every plane in `iccmax`, then reads it back.
+///
+/// Returns for each plane, by plane index, the limit in A read back, or why it failed.
+pub fn set_icc_max(
+    msr: &dyn MsrBackend,
+    iccmax: &HashMap<&'static str, f64>,
+) -> Vec<(&'static str, Result<f64, Error>)> {
+    let mut planes: Vec<_> = iccmax.iter().map(|(k, v)| (*k, *v)).collect();
+    planes.sort_by_key(|(plane, _)| CURRENT_PLANES.get(plane));
+    planes
+        .into_iter()
+        .map(|(plane, current)| {
+            let apply = || {
+                let write_value = calc_icc_max_msr(plane, current)?;
+                write_register(msr, "MSR_OC_MAILBOX", write_value)?;
+                let read_value = get_icc_max(msr, Some(plane))?[plane];
+                if (read_value * 4.0) as u64 == write_value & 0x3FF {
+                    Ok(read_value)
+                } else {
+                    Err(Error::NotApplied(format!(
+                        "{plane} IccMax did not take: wrote {current} A, read back {read_value} A"
+                    )))
+                }
+            };
+            (plane, apply())
+        })
+        .collect()
+}
+
+/// Energy-performance preference for a profile's `HWP_Mode`.
+fn calc_hwp_epp(performance_mode: bool) -> u64 {
+    if performance_mode {
+        HWP_PERFOLRMANCE_VALUE as u64
+    } else {
+        HWP_DEFAULT_VALUE as u64
+    }
+}
+
+/// Sets the energy-performance preference byte of IA32_HWP_REQUEST on every cpu, keeping the
+/// rest of each cpu's request.
+pub fn set_hwp(msr: &dyn MsrBackend, epp: u64) -> Result<(), Error> {
+    let (register, addr) = msr_addr("IA32_HWP_REQUEST")?;
+    for cpu in register_cpus(msr, register)? {
+        let cur_val = msr
+            .read(cpu, addr)
+            .map_err(|source| Error::Read { register, source })?;
+        let new_val = (cur_val & 0xFFFFFFFF00FFFFFF) | ((epp & 0xFF) << 24);
+        if new_val != cur_val {
+            msr.write(cpu, addr, new_val)
+                .map_err(|source| Error::Write {
+                    register,
+                    source: MsrWriteError::Io { cpu, source },
+                })?;
+            debug!("HWP energy-performance preference of cpu {cpu} set to {epp:#x}");
+        }
+    }
+    Ok(())
+}
+
+/// Registers the daemon may write directly, restored by [`RegisterSnapshot`].
+const SNAPSHOT_MSRS: [&str; 5] = [
+    "MSR_PKG_POWER_LIMIT",
+    "MSR_TEMPERATURE_TARGET",
+    "MSR_CONFIG_TDP_CONTROL",
+    "MSR_POWER_CTL",
+    "IA32_HWP_REQUEST",
+];
+
+/// Bits of a [`SNAPSHOT_MSRS`] register the daemon sets, the only ones restored.
+///
+/// intel_pstate keeps the min/max/desired performance of IA32_HWP_REQUEST in line with the
+/// cpufreq policy, so only its EPP is put back. Of MSR_POWER_CTL only BDPROCHOT is.
+fn snapshot_mask(arg: &str) -> u64 {
+    match arg {
+        "IA32_HWP_REQUEST" => 0xFF00_0000,
+        "MSR_POWER_CTL" => 1,
+        _ => u64::MAX,
+    }
+}
+
+/// State of every setting the daemon may change, taken before it changes any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterSnapshot {
+    /// Value of the readable [`SNAPSHOT_MSRS`] as `(cpu, value)`, one per instance of the
+    /// register.
+    pub msrs: Vec<(&'static str, Vec<(usize, u64)>)>,
+    /// Voltage offset mailbox data word per plane, restored as is.
+    pub undervolt: HashMap<&'static str, u64>,
+    /// Current limit in A per plane.
+    pub iccmax: HashMap<&'static str, f64>,
+}
+
+impl RegisterSnapshot {
+    /// Reads the current state. Settings the CPU doesn't let us read are left out.
+    pub fn take(msr: &dyn MsrBackend, unsupported_features: &Vec<&'static str>) -> Self {
+        let msrs = SNAPSHOT_MSRS
+            .into_iter()
+            .filter_map(|arg| {
+                let (addr, scope) = MSR_DICT[arg];
+                let values: io::Result<Vec<_>> = scope_cpus(msr, scope)
+                    .into_iter()
+                    .map(|cpu| Ok((cpu, msr.read(cpu, addr)?)))
+                    .collect();
+                Some((arg, values.ok()?))
+            })
+            .collect();
+        let undervolt = if unsupported_features.contains(&"UNDERVOLT") {
+            HashMap::new()
+        } else {
+            get_undervolt(
+                msr,
+                unsupported_features,
+                None,
+                false,
+                Arc::new(Mutex::new(false)),
+            )
+            .map(|planes| {
+                planes
+                    .into_iter()
+                    .map(|(plane, data)| (plane, data as u64))
+                    .collect()
+            })
+            .unwrap_or_default()
+        };
+        RegisterSnapshot {
+            msrs,
+            undervolt,
+            iccmax: get_icc_max(msr, None).unwrap_or_default(),
+        }
+    }
+
+    /// Adds the registers of `cpus` the snapshot has no value for yet, e.g. cpus that were
+    /// offline when it was taken.
+    ///
+    /// Cpus it has values for keep them, the daemon may have changed their registers since.
+    pub fn add_cpus(&mut self, msr: &dyn MsrBackend, cpus: &[usize]) {
+        for (arg, values) in &mut self.msrs {
+            let (addr, scope) = MSR_DICT[*arg];
+            for cpu in scope_cpus(msr, scope) {
+                if !cpus.contains(&cpu) || values.iter().any(|(known, _)| *known == cpu) {
+                    continue;
+                }
+                match msr.read(cpu, addr) {
+                    Ok(value) => values.push((cpu, value)),
+                    Err(e) => warn!("Unable to read {arg} on cpu {cpu}, it won't be restored: {e}"),
+                }
+            }
+            values.sort();
+        }
+    }
+
+    /// Writes back every setting that changed since the snapshot was taken, see
+    /// [`snapshot_mask`] for the registers only partly restored.
+    pub fn restore(&self, msr: &dyn MsrBackend, unsupported_features: &Vec<&'static str>) {
+        for (arg, values) in &self.msrs {
+            let (addr, _) = MSR_DICT[arg];
+            let mask = snapshot_mask(arg);
+            for &(cpu, snapshot) in values {
+                let current = match msr.read(cpu, addr) {
+                    Ok(current) => current,
+                    Err(e) => {
+                        warn!("Unable to restore {arg} on cpu {cpu}: {e}");
+                        continue;
+                    }
+                };
+                let value = (current & !mask) | (snapshot & mask);
+                if value == current {
+                    continue;
+                }
+                match msr.write(cpu, addr, value) {
+                    Ok(()) => debug!("Restored {arg} on cpu {cpu} to {value:#x}"),
+                    Err(e) => warn!("Unable to restore {arg} on cpu {cpu}: {e}"),
+                }
+            }
+        }
+        let current = RegisterSnapshot::take(msr, unsupported_features);
+        let mut undervolt: Vec<_> = self
+            .undervolt
+            .iter()
+            .filter(|(plane, data)| current.undervolt.get(*plane) != Some(data))
+            .collect();
+        undervolt.sort_by_key(|(plane, _)| VOLTAGE_PLANES[*plane]);
+        for (plane, &data) in undervolt {
+            let restore = || {
+                let command = 0x8000001100000000 | (VOLTAGE_PLANES[plane] << 40) | data;
+                write_register(msr, "MSR_OC_MAILBOX", command)?;
+                let read = get_undervolt(
+                    msr,
+                    unsupported_features,
+                    Some(plane),
+                    false,
+                    Arc::new(Mutex::new(false)),
+                )?;
+                let read_value = read[plane] as u64;
+                if read_value == data {
+                    Ok(())
+                } else {
+                    Err(Error::NotApplied(format!(
+                        "{plane} offset did not take: wrote {data:#x}, read back {read_value:#x}"
+                    )))
+                }
+            };
+            match restore() {
+                Ok(()) => info!("Restored undervolt {plane}: {} mV", calc_undervolt_mv(data)),
+                Err(e) => warn!("Unable to restore {plane} undervolt: {e}"),
+            }
+        }
+        let iccmax: HashMap<_, _> = self
+            .iccmax
+            .iter()
+            .filter(|(plane, a)| current.iccmax.get(*plane) != Some(a))
+            .map(|(plane, a)| (*plane, *a))
+            .collect();
+        for (plane, result) in set_icc_max(msr, &iccmax) {
+            match result {
+                Ok(current) => info!("Restored IccMax {plane}: {current} A"),
+                Err(e) => warn!("Unable to restore {plane} IccMax: {e}"),
+            }
+        }
+    }
+}
+
+/// Decodes the fields of register `arg` holding `value`, as `(field, value)` pairs.
+///
+/// Power limits and time windows are in the units of the `rapl_power_unit` MSR_RAPL_POWER_UNIT
+/// value. Registers without known fields decode to nothing.
+pub fn decode_msr(arg: &str, value: u64, rapl_power_unit: u64) -> Vec<(&'static str, String)> {
+    let (power_unit, time_unit) = calc_rapl_units(rapl_power_unit);
+    let bits = |offset: u32, width: u32| (value >> offset) & ((1 << width) - 1);
+    let flag = |offset| (bits(offset, 1) == 1).to_string();
+    let power = |offset| format!("{} W", bits(offset, 15) as f64 * power_unit);
+    let window = |offset| {
+        let (y, z) = (bits(offset, 5), bits(offset + 5, 2));
+        let seconds = 2.0_f64.powi(y as i32) * (1.0 + z as f64 / 4.0) * time_unit;
+        format!("{seconds} s")
+    };
+    let plane_name = |planes: &HashMap<&'static str, u64>| {
+        let plane = bits(40, 3);
+        planes
+            .iter()
+            .find(|(_, index)| **index == plane)
+            .map_or(plane.to_string(), |(name, _)| name.to_string())
+    };
+    match arg {
+        "MSR_PKG_POWER_LIMIT" | "MCHBAR_PKG_POWER_LIMIT" => vec![
+            ("PL1", power(0)),
+            ("PL1 enabled", flag(15)),
+            ("PL1 clamping", flag(16)),
+            ("PL1 time window", window(17)),
+            ("PL2", power(32)),
+            ("PL2 enabled", flag(47)),
+            ("PL2 clamping", flag(48)),
+            ("PL2 time window", window(49)),
+            ("Locked", flag(63)),
+        ],
+        "MSR_TEMPERATURE_TARGET" => {
+            let (tjmax, offset) = (bits(16, 8), bits(24, 6));
+            vec![
+                ("TjMax", format!("{tjmax} C")),
+                ("Offset", format!("{offset} C")),
+                (
+                    "Trip temperature",
+                    format!("{} C", tjmax.saturating_sub(offset)),
+                ),
+                ("Locked", flag(31)),
+            ]
+        }
+        "MSR_CONFIG_TDP_CONTROL" => vec![("Level", bits(0, 2).to_string()), ("Locked", flag(31))],
+        "MSR_POWER_CTL" => vec![("BDPROCHOT", flag(0))],
+        "IA32_HWP_REQUEST" => vec![
+            ("Minimum performance", bits(0, 8).to_string()),
+            ("Maximum performance", bits(8, 8).to_string()),
+            ("Desired performance", bits(16, 8).to_string()),
+            (
+                "Energy-performance preference",
+                format!("{:#x}", bits(24, 8)),
+            ),
+        ],
+        "MSR_PLATFORM_INFO" => {
+            let info = PlatformInfo::from_msr(value);
+            vec![
+                (
+                    "Maximum non-turbo ratio",
+                    info.maximum_non_turbo_ratio.to_string(),
+                ),
+                ("PPIN", info.ppin_cap.to_string()),
+                (
+                    "Programmable ratio limit",
+                    info.programmable_ratio_limit.to_string(),
+                ),
+                (
+                    "Programmable TDP limit",
+                    info.programmable_tdp_limit.to_string(),
+                ),
+                (
+                    "Programmable TJ offset",
+                    info.programmable_tj_offset.to_string(),
+                ),
+                ("Low power mode", info.low_power_mode.to_string()),
+                ("cTDP levels", info.config_tdp_levels.to_string()),
+                (
+                    "Maximum efficiency ratio",
+                    info.maximum_efficiency_ratio.to_string(),
+                ),
+                (
+                    "Minimum operating ratio",
+                    info.minimum_operating_ratio.to_string(),
+                ),
+            ]
+        }
+        "IA32_PERF_STATUS" => vec![
+            ("Ratio", bits(8, 8).to_string()),
+            (
+                "Voltage",
+                format!("{:.0} mV", bits(32, 16) as f64 / 2.0_f64.powi(13) * 1000.0),
+            ),
+        ],
+        "IA32_THERM_STATUS" => vec![
+            ("Thermal limit", flag(0)),
+            ("Power limit", flag(10)),
+            ("Current limit", flag(12)),
+            ("Cross-domain limit", flag(14)),
+            ("Below TjMax", format!("{} C", bits(16, 7))),
+        ],
+        "IA32_PACKAGE_THERM_STATUS" => vec![
+            ("Thermal limit", flag(0)),
+            ("Power limit", flag(10)),
+            ("Below TjMax", format!("{} C", bits(16, 7))),
+        ],
+        "MSR_INTEL_PKG_ENERGY_STATUS" | "MSR_PP1_ENERGY_STATUS" | "MSR_DRAM_ENERGY_STATUS" => {
+            vec![(
+                "Energy",
+                format!(
+                    "{} J",
+                    bits(0, 32) as f64 * calc_energy_unit(rapl_power_unit)
+                ),
+            )]
+        }
+        "MSR_RAPL_POWER_UNIT" => vec![
+            ("Power unit", format!("{power_unit} W")),
+            ("Energy unit", format!("{} J", calc_energy_unit(value))),
+            ("Time unit", format!("{time_unit} s")),
+        ],
+        "MSR_OC_MAILBOX" => match bits(32, 8) {
+            0x10 | 0x11 => vec![
+                ("Plane", plane_name(&VOLTAGE_PLANES)),
+                ("Offset", format!("{} mV", calc_undervolt_mv(value))),
+            ],
+            0x16 | 0x17 => vec![
+                ("Plane", plane_name(&CURRENT_PLANES)),
+                ("IccMax", format!("{} A", bits(0, 10) as f64 / 4.0)),
+            ],
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+/// A register write kept back by [`DryRunMsr`], with the value it would replace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedWrite {
+    pub register: &'static str,
+    /// `None` when it can't be read without side effects.
+    pub before: Option<u64>,
+    pub after: u64,
+}
+
+/// Reads the current value behind every write planned on `msr`, skipping writes that
+/// wouldn't change anything.
+///
+/// MSR_OC_MAILBOX writes are given as write commands. Reading the mailbox means sending it a
+/// command, so their `before` is unknown and they are always listed.
+pub fn plan_writes<M: MsrBackend>(msr: &DryRunMsr<M>) -> Result<Vec<PlannedWrite>, Error> {
+    let mut plan = Vec::new();
+    for (addr, after) in msr.planned() {
+        let register = MSR_DICT
+            .iter()
+            .find(|(_, (a, _))| *a == addr)
+            .map(|(name, _)| *name)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown register {addr:#x}")))?;
+        let before = if register == "MSR_OC_MAILBOX" {
+            None
+        } else {
+            Some(readmsr_flat(msr.inner(), register, None, None)?)
+        };
+        if before != Some(after) {
+            plan.push(PlannedWrite {
+                register,
+                before,
+                after,
+            });
+        }
+    }
+    Ok(plan)
+}
+
+/// Prints `plan` as a table, with the fields of each register before and after.
+fn print_plan(plan: &[PlannedWrite], rapl_power_unit: u64) {
+    if plan.is_empty() {
+        println!("Every register already has its target value, nothing to write.");
+        return;
+    }
+    println!("{:<24} {:>18}    {:>18}", "Register", "Before", "After");
+    for write in plan {
+        let after = decode_msr(write.register, write.after, rapl_power_unit);
+        let Some(before) = write.before else {
+            println!(
+                "{:<24} {:>18} -> {:#018x}",
+                write.register, "unknown", write.after
+            );
+            for (field, new) in &after {
+                println!("    {field}: {new}");
+            }
+            continue;
+        };
+        println!(
+            "{:<24} {before:#018x} -> {:#018x}",
+            write.register, write.after
+        );
+        let before = decode_msr(write.register, before, rapl_power_unit);
+        for ((field, old), (_, new)) in before.iter().zip(&after) {
+            if old == new {
+                println!("    {field}: {new}");
+            } else {
+                println!("    {field}: {old} -> {new}");
+            }
+        }
+    }
+}
+
+/// Value of a register on each online cpu as `(cpu, value)`, `None` where the read failed.
+pub type CpuValues = Vec<(usize, Option<u64>)>;
+
+/// Every register of [`MSR_DICT`] read on each cpu, for the `regs` subcommand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterDump {
+    /// Values by register, ordered by address.
+    pub registers: Vec<(&'static str, u64, CpuValues)>,
+    /// Voltage offset in mV per plane, by plane index. Empty if undervolt can't be read.
+    pub undervolt: Vec<(&'static str, i64)>,
+}
+
+impl RegisterDump {
+    pub fn read(msr: &dyn MsrBackend) -> Self {
+        let mut registers: Vec<_> = MSR_DICT
+            .iter()
+            .map(|(name, (addr, _))| {
+                let values = msr
+                    .cpus()
+                    .into_iter()
+                    .map(|cpu| (cpu, msr.read(cpu, *addr).ok()))
+                    .collect();
+                (*name, *addr, values)
+            })
+            .collect();
+        registers.sort_by_key(|(_, addr, _)| *addr);
+        // Probing mode, so a refused read isn't escalated.
+        let test_msr = Arc::new(Mutex::new(true));
+        let mut undervolt: Vec<_> = get_undervolt(msr, &vec![], None, true, test_msr)
+            .map(|planes| planes.into_iter().collect())
+            .unwrap_or_default();
+        undervolt.sort_by_key(|(plane, _)| VOLTAGE_PLANES[plane]);
+        RegisterDump {
+            registers,
+            undervolt,
+        }
+    }
+
+    fn rapl_power_unit(&self) -> u64 {
+        self.registers
+            .iter()
+            .find(|(name, _, _)| *name == "MSR_RAPL_POWER_UNIT")
+            .and_then(|(_, _, values)| values.iter().find_map(|(_, value)| *value))
+            .unwrap_or_default()
+    }
+
+    /// Human readable dump, grouping the cpus that share a value. `raw` leaves out the fields.
+    pub fn to_text(&self, raw: bool) -> String {
+        let rapl_power_unit = self.rapl_power_unit();
+        let mut out = String::new();
+        for (name, addr, values) in &self.registers {
+            out += &format!("{name} ({addr:#x})\n");
+            let mut groups: Vec<(Option<u64>, Vec<usize>)> = Vec::new();
+            for &(cpu, value) in values {
+                match groups.iter_mut().find(|(v, _)| *v == value) {
+                    Some((_, cpus)) => cpus.push(cpu),
+                    None => groups.push((value, vec![cpu])),
+                }
+            }
+            for (value, cpus) in groups {
+                let cpus = format_cpu_list(&cpus);
+                let Some(value) = value else {
+                    out += &format!("  cpu {cpus}: unreadable\n");
+                    continue;
+                };
+                out += &format!("  cpu {cpus}: {value:#018x}\n");
+                if !raw {
+                    for (field, decoded) in decode_msr(name, value, rapl_power_unit) {
+                        out += &format!("    {field}: {decoded}\n");
+                    }
+                }
+            }
+        }
+        if !self.undervolt.is_empty() {
+            out += "Undervolt\n";
+            for (plane, mv) in &self.undervolt {
+                out += &format!("  {plane}: {mv} mV\n");
+            }
+        }
+        out
+    }
+
+    /// JSON dump, with the values keyed by cpu. Values are hex strings since they don't all fit
+    /// in a JSON number.
+    pub fn to_json(&self, raw: bool) -> String {
+        let rapl_power_unit = self.rapl_power_unit();
+        let registers: Vec<_> = self
+            .registers
+            .iter()
+            .map(|(name, addr, values)| {
+                let hex: Vec<_> = values
+                    .iter()
+                    .map(|(cpu, v)| {
+                        let v = v.map_or("null".to_owned(), |v| format!("\"{v:#x}\""));
+                        format!("\"{cpu}\": {v}")
+                    })
+                    .collect();
+                let mut entry = format!(
+                    "\"address\": \"{addr:#x}\", \"values\": {{{}}}",
+                    hex.join(", ")
+                );
+                if let (false, Some(value)) = (raw, values.iter().find_map(|(_, v)| v.as_ref())) {
+                    let fields: Vec<_> = decode_msr(name, *value, rapl_power_unit)
+                        .into_iter()
+                        .map(|(field, decoded)| {
+                            format!("{}: {}", json_string(field), json_string(&decoded))
+                        })
+                        .collect();
+                    entry += &format!(", \"fields\": {{{}}}", fields.join(", "));
+                }
+                format!("    {}: {{{entry}}}", json_string(name))
+            })
+            .collect();
+        let undervolt: Vec<_> = self
+            .undervolt
+            .iter()
+            .map(|(plane, mv)| format!("{}: {mv}", json_string(plane)))
+            .collect();
+        format!(
+            "{{\n  \"registers\": {{\n{}\n  }},\n  \"undervolt_mv\": {{{}}}\n}}\n",
+            registers.join(",\n"),
+            undervolt.join(", ")
+        )
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if c.is_control() => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats sorted cpu numbers the way sysfs does, e.g. `0-3,6`.
+fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Represents the information obtained from the `uname` system call.
+///
+/// Corresponds to the `struct utsname` in C.
+#[derive(Debug)]
+pub struct UnameInfo {
+    pub sysname: String,     // Operating system name (e.g., "Linux", "Darwin")
+    pub nodename: String,    // Network node hostname (e.g., "my-laptop")
+    pub pub_release: String, // Operating system release (e.g., "5.15.0-76-generic")
+    pub version: String, // Operating system version (e.g., "#83~22.04.1-Ubuntu SMP PREEMPT_DYNAMIC...")
+    pub machine: String, // Hardware identifier (e.g., "x86_64", "arm64")
+    // domainname is present on some systems (like Linux) but not others (like macOS).
+    // We'll handle it conditionally.
+    pub domainname: Option<String>,
+}
+
+/// Helper function to convert a null-terminated C char array to a Rust String.
+fn c_char_array_to_string(arr: &[c_char]) -> io::Result<String> {
+    // Safety: CStr::from_ptr expects a null-terminated string.
+    // We assume the C array is null-terminated as per uname() contract.
+    let c_str = unsafe { CStr::from_ptr(arr.as_ptr()) };
+    c_str.to_str().map(|s| s.to_owned()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid UTF-8 in uname string: {}", e),
+        )
+    })
+}
+
+/// Retrieves system information using the `uname` system call.
+///
+/// Returns a `Result` containing `UnameInfo` on success or an `io::Error` on failure.
+pub fn get_uname_info() -> io::Result<UnameInfo> {
+    // Create an uninitialized utsname struct.
+    // std::mem::zeroed is safe here because uname() will fully initialize it.
+    let mut info = unsafe { std::mem::zeroed::<libc::utsname>() };
+
+    let result = unsafe {
+        // Call the uname system function.
+        // It takes a mutable pointer to the utsname struct.
+        libc::uname(&mut info)
+    };
+
+    if result < 0 {
+        // If uname returns a negative value, an error occurred.
+        // Get the last OS error (errno) and convert it to an io::Error.
+        return Err(io::Error::last_os_error());
+    }
+
+    // Convert each C string field to a Rust String.
+    let sysname = c_char_array_to_string(&info.sysname)?;
+    let nodename = c_char_array_to_string(&info.nodename)?;
+    let release = c_char_array_to_string(&info.release)?;
+    let version = c_char_array_to_string(&info.version)?;
+    let machine = c_char_array_to_string(&info.machine)?;
+
+    // The 'domainname' field is platform-specific and not always present
+    // or filled. It's typically part of `utsname` on Linux, but not macOS.
+    let domainname = {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            // On Linux/Android, utsname has a domainname field.
+            let domain_str = c_char_array_to_string(&info.domainname)?;
+            if domain_str.is_empty() {
+                None
+            } else {
+                Some(domain_str)
+            }
+        }
+    };
+
+    Ok(UnameInfo {
+        sysname,
         nodename,
         pub_release: release,
         version,
@@ -505,9 +3128,9 @@ pub fn get_uname_info() -> io::Result<UnameInfo> {
     })
 }
 
-fn check_kernel() {
+fn check_kernel() -> Result<(), Error> {
     if unsafe { libc::geteuid() } != 0 {
-        fatal("No root no party. Try again with sudo.");
+        return Err(Error::NotRoot);
     }
     let kernel_config = get_uname_info()
         .and_then(|info| {
@@ -523,46 +3146,64 @@ fn check_kernel() {
             Command::new("modprobe").arg("configs").status()?;
             proc_gz.read_to_string(&mut buf).map(|_| buf)
         });
-    let data =
-        kernel_config.unwrap_or_else(|_| fatal("Unable to obtain and validate kernel config."));
+    let data = kernel_config
+        .map_err(|e| Error::Kernel(format!("unable to obtain and validate it: {e}")))?;
 
     if !data.contains("CONFIG_DEVMEM=y") {
         warn!("Bad kernel config: you need CONFIG_DEVMEM=y");
     }
     if !data.contains("CONFIG_X86_MSR=y") && !data.contains("CONFIG_X86_MSR=m") {
-        fatal("Bad kernel config: you need CONFIG_X86_MSR builtin or as module.");
+        return Err(Error::Kernel(
+            "you need CONFIG_X86_MSR builtin or as module.".to_owned(),
+        ));
     }
+    Ok(())
 }
 
-fn check_cpu() -> Option<CpuId> {
-    let mut f =
-        File::open("/proc/cpuinfo").unwrap_or_else(|_| fatal("Unable to identify CPU model."));
+fn check_cpu() -> Result<CpuId, Error> {
+    let unidentified = || Error::UnsupportedCpu("Unable to identify CPU model.".to_owned());
     let mut buf = String::new();
-    f.read_to_string(&mut buf).ok()?;
-    let cpuinfo: HashMap<&str, &str> = buf.lines().flat_map(|l| l.split_once(':')).collect();
+    File::open("/proc/cpuinfo")
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .map_err(|_| unidentified())?;
+    let cpuinfo: HashMap<&str, &str> = buf
+        .lines()
+        .flat_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .collect();
     if cpuinfo
         .get("vendor_id")
         .is_none_or(|v| *v != "GenuineIntel")
     {
-        fatal("This tool is designed for Intel CPUs only.");
+        return Err(Error::UnsupportedCpu(
+            "This tool is designed for Intel CPUs only.".to_owned(),
+        ));
+    }
+    let field = |name| cpuinfo.get(name)?.parse().ok();
+    match (field("cpu family"), field("model"), field("stepping")) {
+        (Some(cpu_family), Some(model), Some(stepping)) => Ok((cpu_family, model, stepping)),
+        _ => Err(unidentified()),
     }
-    let cpu_family = cpuinfo.get("cpu family")?.parse().ok()?;
-    let model = cpuinfo.get("model")?.parse().ok()?;
-    let stepping = cpuinfo.get("stepping")?.parse().ok()?;
-    Some((cpu_family, model, stepping))
 }
 
-pub fn calc_icc_max_msr(plane: &str, current: f64) -> u64 {
+pub fn calc_icc_max_msr(plane: &str, current: f64) -> Result<u64, Error> {
     let plane_idx = match plane {
         "CORE" => 0,
         "GPU" => 1,
         "CACHE" => 2,
-        _ => panic!("Invalid plane"),
+        _ => {
+            return Err(Error::InvalidArgument(format!(
+                "plane {plane} not found in CURRENT_PLANES"
+            )))
+        }
     };
     let current_val = (current * 4.0).round() as u64;
-    0x8000001700000000 | (plane_idx << 40) | current_val
+    Ok(0x8000001700000000 | (plane_idx << 40) | current_val)
 }
 
+/// Encodes the time window `t` as the `(Y, Z)` pair of `2^Y * (1 + Z/4)` time units.
+///
+/// Windows longer than the encoding allows get the longest one.
 pub fn calc_time_window_vars(t: f64, time_unit: f64) -> (u64, u64) {
     for y in 0..32 {
         for z in 0..4 {
@@ -572,39 +3213,606 @@ pub fn calc_time_window_vars(t: f64, time_unit: f64) -> (u64, u64) {
             }
         }
     }
-    panic!("No window found");
+    let longest = 2.0_f64.powi(31) * 1.75 * time_unit;
+    warn!("Time window {t} s is above the longest encodable one, using {longest} s");
+    (31, 3)
 }
 
-pub fn main_loop() {
-    let args = parse_args();
+/// State shared by the glib callbacks of the daemon.
+pub struct Daemon {
+    msr: Box<dyn MsrBackend>,
+    config_path: PathBuf,
+    settings: RefCell<Settings>,
+    platform_info: PlatformInfo,
+    power_source: Cell<PowerSource>,
+    mchbar: Option<Mmio>,
+    unsupported_features: Vec<&'static str>,
+    update_timer: RefCell<Option<SourceId>>,
+    /// Settings found at startup, or when a cpu first came online, restored on exit.
+    snapshot: RefCell<RegisterSnapshot>,
+    /// MCHBAR power limit found at startup, if it could be read.
+    mchbar_snapshot: Option<u64>,
+    /// BDPROCHOT was already disabled once.
+    bdprochot_disabled: Cell<bool>,
+}
 
-    let cpuid: Option<CpuId> = if !args.force {
-        check_kernel();
-        Some(check_cpu().unwrap_or_else(|| fatal("Unable to identify CPU model.")))
-    } else {
-        None
-    };
-    let family_name = cpuid.and_then(|x| CPUMAP.get(&x));
-    match family_name {
-        Some(name) => info!("Detected CPU architecture: Intel {}", name),
-        None => fatal("Your CPU model is not supported."),
+impl Daemon {
+    /// Takes the snapshot of the current settings, before the daemon changes any.
+    pub fn new(
+        msr: Box<dyn MsrBackend>,
+        config_path: PathBuf,
+        settings: Settings,
+        platform_info: PlatformInfo,
+        power_source: PowerSource,
+        unsupported_features: Vec<&'static str>,
+        mchbar: Option<Mmio>,
+    ) -> Self {
+        let snapshot = RegisterSnapshot::take(msr.as_ref(), &unsupported_features);
+        debug!("{snapshot:?}");
+        let mchbar_snapshot = mchbar.as_ref().and_then(|mchbar| {
+            read_mchbar_power_limit(mchbar)
+                .inspect_err(|e| warn!("Unable to read the MCHBAR power limit: {e}"))
+                .ok()
+        });
+        Daemon {
+            msr,
+            config_path,
+            settings: RefCell::new(settings),
+            platform_info,
+            power_source: Cell::new(power_source),
+            mchbar,
+            unsupported_features,
+            update_timer: RefCell::new(None),
+            snapshot: RefCell::new(snapshot),
+            mchbar_snapshot,
+            bdprochot_disabled: Cell::new(false),
+        }
+    }
+
+    /// Applies the settings of the active profile that only need to be written once.
+    fn apply_profile(&self) {
+        let settings = self.settings.borrow();
+        let config = &settings.config;
+        let profile = config.profile(self.power_source.get());
+        if !profile.undervolt.is_empty() && !self.unsupported_features.contains(&"UNDERVOLT") {
+            for (plane, result) in set_undervolt(
+                self.msr.as_ref(),
+                &self.unsupported_features,
+                &profile.undervolt,
+            ) {
+                match result {
+                    Ok(offset) => info!("Undervolt {plane}: {offset} mV"),
+                    Err(e) => warn!("Unable to undervolt {plane}: {e}"),
+                }
+            }
+        }
+        for (plane, result) in set_icc_max(self.msr.as_ref(), &profile.iccmax) {
+            match result {
+                Ok(current) => info!("IccMax {plane}: {current} A"),
+                Err(e) => warn!("Unable to set {plane} IccMax: {e}"),
+            }
+        }
+        self.apply_hwp();
+    }
+
+    /// Writes the `HWP_Mode` of the active profile, if it has one.
+    fn apply_hwp(&self) {
+        let settings = self.settings.borrow();
+        let config = &settings.config;
+        let profile = config.profile(self.power_source.get());
+        let Some(performance_mode) = profile.hwp_mode else {
+            return;
+        };
+        if self.unsupported_features.contains(&"HWP") {
+            return;
+        }
+        if let Err(e) = set_hwp(self.msr.as_ref(), calc_hwp_epp(performance_mode)) {
+            warn!("Unable to set HWP mode: {e}");
+        }
+    }
+
+    /// Re-asserts the HWP mode every [`HWP_INTERVAL`] seconds.
+    ///
+    /// power-profiles-daemon, TLP and the kernel keep overwriting it.
+    fn schedule_hwp(self: &Rc<Self>) {
+        let daemon = self.clone();
+        glib::timeout_add_seconds_local(HWP_INTERVAL as u32, move || {
+            daemon.apply_hwp();
+            ControlFlow::Continue
+        });
+    }
+
+    /// Rewrites the undervolt planes of the active profile whose offset was reset.
+    fn verify_undervolt(&self) {
+        let settings = self.settings.borrow();
+        let config = &settings.config;
+        let profile = config.profile(self.power_source.get());
+        if profile.undervolt.is_empty() || self.unsupported_features.contains(&"UNDERVOLT") {
+            return;
+        }
+        let current = match get_undervolt(
+            self.msr.as_ref(),
+            &self.unsupported_features,
+            None,
+            true,
+            Arc::new(Mutex::new(false)),
+        ) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Unable to verify undervolt: {e}");
+                return;
+            }
+        };
+        let reset: HashMap<_, _> = profile
+            .undervolt
+            .iter()
+            .filter(|(plane, mv)| {
+                let target = calc_undervolt_msr(plane, **mv).map(calc_undervolt_mv);
+                target.ok() != current.get(*plane).copied()
+            })
+            .map(|(plane, mv)| (*plane, *mv))
+            .collect();
+        for (plane, result) in set_undervolt(self.msr.as_ref(), &self.unsupported_features, &reset)
+        {
+            match result {
+                Ok(offset) => info!("Undervolt {plane} was reset, set it back to {offset} mV"),
+                Err(e) => warn!("Unable to undervolt {plane}: {e}"),
+            }
+        }
+    }
+
+    /// Re-applies the settings `cpus` may have lost while they were offline.
+    ///
+    /// Cpus coming online for the first time get their registers added to the snapshot first.
+    pub fn cpus_online(&self, cpus: &[usize]) {
+        info!(
+            "cpu {} came online, re-applying settings.",
+            format_cpu_list(cpus)
+        );
+        self.snapshot.borrow_mut().add_cpus(self.msr.as_ref(), cpus);
+        self.apply_hwp();
+        self.verify_undervolt();
+        self.apply_regs();
+    }
+
+    /// Puts back the settings found at startup, unless `Restore_On_Exit` is off.
+    pub fn shutdown(&self) {
+        if !self.settings.borrow().config.general.restore_on_exit {
+            info!("Leaving the current settings in place.");
+            return;
+        }
+        info!("Restoring the original settings.");
+        self.snapshot
+            .borrow()
+            .restore(self.msr.as_ref(), &self.unsupported_features);
+        if let (Some(mchbar), Some(value)) = (&self.mchbar, self.mchbar_snapshot) {
+            if let Err(e) = write_mchbar_power_limit(mchbar, value) {
+                warn!("Unable to restore the MCHBAR power limit: {e}");
+            }
+        }
+    }
+
+    /// Re-applies the registers of the active profile, then schedules the next update after its
+    /// `Update_Rate_s`.
+    ///
+    /// Firmware resets the power limits behind our back, so every update compares them against
+    /// their target and rewrites the ones that drifted.
+    fn update(self: &Rc<Self>) {
+        self.apply_regs();
+        let update_rate = self
+            .settings
+            .borrow()
+            .config
+            .profile(self.power_source.get())
+            .update_rate_s;
+        let daemon = self.clone();
+        let timer = glib::timeout_add_local_once(Duration::from_secs_f64(update_rate), move || {
+            daemon.update_timer.take();
+            daemon.update();
+        });
+        self.update_timer.replace(Some(timer));
+    }
+
+    /// Rewrites the power limits, trip temperature, cTDP level and BDPROCHOT of the active
+    /// profile where they drifted.
+    fn apply_regs(&self) {
+        let power_source = self.power_source.get();
+        let settings = self.settings.borrow();
+        let config = &settings.config;
+        if let Some(regs) = settings.regs.get(&power_source) {
+            let written = apply_regs(self.msr.as_ref(), regs);
+            if written > 0 {
+                debug!("Rewrote {written} register(s) for {power_source}");
+            }
+            let mchbar = self
+                .mchbar
+                .as_ref()
+                .filter(|_| config.general.mchbar_mirror);
+            if let (Some(mchbar), Some(&value)) = (mchbar, regs.get("MSR_PKG_POWER_LIMIT")) {
+                match read_mchbar_power_limit(mchbar) {
+                    Ok(current) if current == value => {}
+                    Ok(current) => {
+                        debug!("MCHBAR PKG_POWER_LIMIT drifted to {current:#x}, target {value:#x}");
+                        if let Err(e) = write_mchbar_power_limit(mchbar, value) {
+                            warn!("Unable to set the MCHBAR power limit: {e}");
+                        }
+                    }
+                    Err(e) => warn!("Unable to read the MCHBAR power limit: {e}"),
+                }
+            }
+        }
+        if config.profile(power_source).disable_bdprochot {
+            match set_disable_bdprochot(self.msr.as_ref()) {
+                Ok(was_set) => {
+                    let disabled_before = self.bdprochot_disabled.replace(true);
+                    match (was_set.as_slice(), disabled_before) {
+                        ([], _) => {}
+                        (cpus, true) => warn!(
+                            "BDPROCHOT was re-enabled by the firmware on cpu {}, disabling it again.",
+                            format_cpu_list(cpus)
+                        ),
+                        (_, false) => info!("BDPROCHOT disabled."),
+                    }
+                }
+                Err(e) => warn!("Unable to disable BDPROCHOT: {e}"),
+            }
+        }
+    }
+
+    /// Re-reads the config file and switches to it, unless it is invalid.
+    fn reload(self: &Rc<Self>) {
+        info!("Reloading {}", self.config_path.display());
+        let current = self.settings.borrow().config.general.clone();
+        let reloaded = self.settings.borrow_mut().reload(
+            self.msr.as_ref(),
+            &self.config_path,
+            &self.platform_info,
+        );
+        let diff = match reloaded {
+            Ok(diff) => diff,
+            Err(e) => {
+                warn!("{e}, keeping the current configuration.");
+                return;
+            }
+        };
+        if diff.is_empty() {
+            info!("Configuration unchanged.");
+            return;
+        }
+        for change in diff {
+            info!("{change}");
+        }
+        let general = self.settings.borrow().config.general.clone();
+        if !general.enabled {
+            warn!("Disabling only takes effect after a restart.");
+        }
+        if general.autoreload && !current.autoreload {
+            warn!("Autoreload only takes effect after a restart, reload with SIGHUP until then.");
+        }
+        if general.mchbar_mirror && !current.mchbar_mirror && self.mchbar.is_none() {
+            warn!("MCHBAR_Mirror only takes effect after a restart.");
+        }
+        self.set_power_source(self.power_source.get());
+    }
+
+    /// Switches to the profile of `source` right away.
+    fn set_power_source(self: &Rc<Self>, source: PowerSource) {
+        self.power_source.set(source);
+        self.apply_profile();
+        if let Some(timer) = self.update_timer.take() {
+            timer.remove();
+        }
+        self.update();
+    }
+}
+
+/// IA32_THERM_STATUS real-time status bits of the causes monitor mode reports.
+const THROTTLE_CAUSES: [(&str, u32); 4] = [
+    ("Thermal", 0),
+    ("Power", 10),
+    ("Current", 12),
+    ("Cross-domain (e.g. GPU)", 14),
+];
+
+/// Realtime view of throttling causes and power draw, read from CPU 0.
+pub struct Monitor {
+    tjmax: Option<f64>,
+    ctdp: bool,
+    energy: EnergySampler,
+}
+
+impl Monitor {
+    pub fn new(msr: &dyn MsrBackend, platform_info: &PlatformInfo) -> Result<Self, Error> {
+        let tjmax = read_cpu0(msr, "MSR_TEMPERATURE_TARGET")
+            .ok()
+            .map(calc_critical_temp);
+        let mut energy = EnergySampler::new(msr, 2)?;
+        energy.sample(msr)?;
+        Ok(Monitor {
+            tjmax,
+            ctdp: platform_info.config_tdp_levels > 0,
+            energy,
+        })
+    }
+
+    /// Formats one status line, with the power drawn since the previous one.
+    pub fn sample(
+        &mut self,
+        msr: &dyn MsrBackend,
+        power_source: PowerSource,
+    ) -> Result<String, Error> {
+        self.sample_at(msr, power_source, Instant::now())
+    }
+
+    /// [`Monitor::sample`], taken at `at`.
+    pub fn sample_at(
+        &mut self,
+        msr: &dyn MsrBackend,
+        power_source: PowerSource,
+        at: Instant,
+    ) -> Result<String, Error> {
+        let read = |arg| read_cpu0(msr, arg);
+        let therm_status = read("IA32_THERM_STATUS")?;
+        let causes = THROTTLE_CAUSES
+            .iter()
+            .map(|(cause, bit)| {
+                let state = if therm_status >> bit & 1 != 0 {
+                    "LIM"
+                } else {
+                    "OK"
+                };
+                format!("{cause}: {state}")
+            })
+            .collect::<Vec<_>>()
+            .join(" - ");
+
+        let mut stats = vec![];
+        if let (Some(tjmax), Ok(status)) = (self.tjmax, read("IA32_PACKAGE_THERM_STATUS")) {
+            stats.push(format!(
+                "Temp: {:.0} C",
+                tjmax - (status >> 16 & 0x7F) as f64
+            ));
+        }
+        let vid = readmsr(msr, "IA32_PERF_STATUS", Some(32), Some(47), Some(0))?.first();
+        let vcore = vid as f64 / 2.0_f64.powi(13) * 1000.0;
+        stats.push(format!("VCore: {vcore:.0} mV"));
+        if let (true, Ok(control)) = (self.ctdp, read("MSR_CONFIG_TDP_CONTROL")) {
+            stats.push(format!("cTDP: {}", control & 0x3));
+        }
+        self.energy.sample_at(msr, at)?;
+        let mut total = 0.0;
+        for plane in self.energy.planes() {
+            let watts = self.energy.watts(plane).unwrap_or(0.0);
+            stats.push(format!("{plane}: {watts:.1} W"));
+            total += watts;
+        }
+        stats.push(format!("Total: {total:.1} W"));
+        Ok(format!(
+            "[{power_source}] {causes} || {}",
+            stats.join(" - ")
+        ))
+    }
+}
+
+/// Prints a [`Monitor`] line every `monitor_ms`, overwriting the previous one on a terminal.
+fn start_monitor(daemon: &Rc<Daemon>, monitor: Monitor, args: &Config) {
+    match get_undervolt(
+        daemon.msr.as_ref(),
+        &daemon.unsupported_features,
+        None,
+        true,
+        Arc::new(Mutex::new(false)),
+    ) {
+        Ok(undervolt) => {
+            let mut planes: Vec<_> = undervolt.into_iter().collect();
+            planes.sort_by_key(|(plane, _)| VOLTAGE_PLANES[plane]);
+            let planes: Vec<_> = planes
+                .iter()
+                .map(|(plane, mv)| format!("{plane}: {mv}"))
+                .collect();
+            debug!("Undervolt offsets: {}", planes.join(" | "));
+        }
+        Err(e) => debug!("Unable to read undervolt offsets: {e}"),
+    }
+    match get_icc_max(daemon.msr.as_ref(), None) {
+        Ok(iccmax) => {
+            let mut planes: Vec<_> = iccmax.into_iter().collect();
+            planes.sort_by_key(|(plane, _)| CURRENT_PLANES[plane]);
+            let planes: Vec<_> = planes
+                .iter()
+                .map(|(plane, a)| format!("{plane}: {a:.2}"))
+                .collect();
+            debug!("IccMax: {}", planes.join(" | "));
+        }
+        Err(e) => debug!("Unable to read IccMax: {e}"),
+    }
+    info!("Realtime monitoring of throttling causes:");
+    let daemon = daemon.clone();
+    let log = args.log.clone();
+    let monitor = RefCell::new(monitor);
+    glib::timeout_add_local(Duration::from_millis(args.monitor_ms), move || {
+        let line = monitor
+            .borrow_mut()
+            .sample(daemon.msr.as_ref(), daemon.power_source.get());
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("{e}");
+                return ControlFlow::Continue;
+            }
+        };
+        let _ = match &log {
+            Some(file) => writeln!(file.as_ref(), "{line}"),
+            None => {
+                let mut stdout = io::stdout();
+                write!(stdout, "\r{line}          ").and_then(|()| stdout.flush())
+            }
+        };
+        ControlFlow::Continue
+    });
+}
+
+/// Runs the daemon until SIGINT or SIGTERM. Errors are logged before being returned.
+pub fn main_loop() -> Result<(), Error> {
+    let args = match parse_args()? {
+        ParsedArgs::Run(args) => args,
+        ParsedArgs::Help => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        ParsedArgs::Version => {
+            println!("rsthrottled {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
     };
+    init_logger(&args);
+    run(args).inspect_err(|e| error!("{e}"))
+}
+
+fn run(args: Config) -> Result<(), Error> {
+    if !args.force {
+        check_kernel()?;
+        let cpuid = check_cpu()?;
+        match CPUMAP.get(&cpuid) {
+            Some(name) => info!("Detected CPU architecture: Intel {}", name),
+            None => {
+                return Err(Error::UnsupportedCpu(
+                    "Your CPU model is not supported.".to_owned(),
+                ))
+            }
+        }
+    }
 
-    set_msr_allow_writes();
+    if args.regs {
+        let dump = RegisterDump::read(&DevMsr::new()?);
+        if args.json {
+            print!("{}", dump.to_json(args.raw));
+        } else {
+            print!("{}", dump.to_text(args.raw));
+        }
+        return Ok(());
+    }
+
+    let (msr, dry_run): (Box<dyn MsrBackend>, _) = if args.dry_run {
+        info!("Dry run, no register will be written.");
+        let dry_run = Rc::new(DryRunMsr::new(DevMsr::new()?));
+        (Box::new(dry_run.clone()), Some(dry_run))
+    } else {
+        set_msr_allow_writes();
+        (Box::new(DevMsr::new()?), None)
+    };
     let test_msr = Arc::new(Mutex::new(false));
     let mut unsupported_features: Vec<&'static str> = vec![];
-    test_msr_rw_capabilities(test_msr, &mut unsupported_features);
-    // dbus stuff
-    let power_source = get_power_source();
-    let platform_info = get_platform_info();
-    let config = load_config(args.clone());
-    let regs = get_reg_values();
+    test_msr_rw_capabilities(msr.as_ref(), test_msr.clone(), &mut unsupported_features);
+    let (config, warnings) = load_config(&args.config)?;
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    if !config.general.enabled {
+        info!("Disabled in {}, exiting.", args.config.display());
+        return Ok(());
+    }
+    let bus = system_bus();
+    let power_source = get_power_source(bus.as_ref());
+    info!("Power source: {power_source}");
+    let platform_info = get_platform_info(msr.as_ref())?;
+    debug!("{platform_info:?}");
+    let regs = get_reg_values(msr.as_ref(), &config, &platform_info)?;
 
-    let _ = get_undervolt(&unsupported_features, None, false, test_msr);
-    set_icc_max();
-    set_hwp();
+    let mchbar = config
+        .general
+        .mchbar_mirror
+        .then(map_mchbar_power_limit)
+        .flatten();
+    // MCHBAR writes aren't intercepted, so the daemon doesn't get it in a dry run.
+    let (mchbar, dry_run_mchbar) = match dry_run {
+        Some(_) => (None, mchbar),
+        None => (mchbar, None),
+    };
+    let autoreload = config.general.autoreload;
+    let daemon = Rc::new(Daemon::new(
+        msr,
+        args.config.clone(),
+        Settings { config, regs },
+        platform_info,
+        power_source,
+        unsupported_features,
+        mchbar,
+    ));
+    daemon.apply_profile();
+    if let Some(dry_run) = dry_run {
+        daemon.apply_regs();
+        let mut plan = plan_writes(dry_run.as_ref())?;
+        let target = daemon.settings.borrow().regs[&power_source]
+            .get("MSR_PKG_POWER_LIMIT")
+            .copied();
+        if let (Some(mchbar), Some(after)) = (dry_run_mchbar, target) {
+            match read_mchbar_power_limit(&mchbar) {
+                Ok(before) if before != after => plan.push(PlannedWrite {
+                    register: "MCHBAR_PKG_POWER_LIMIT",
+                    before: Some(before),
+                    after,
+                }),
+                Ok(_) => {}
+                Err(e) => warn!("Unable to read the MCHBAR power limit, leaving it out: {e}"),
+            }
+        }
+        let rapl_power_unit = readmsr_flat(daemon.msr.as_ref(), "MSR_RAPL_POWER_UNIT", None, None)?;
+        print_plan(&plan, rapl_power_unit);
+        return Ok(());
+    }
+    daemon.update();
+    daemon.schedule_hwp();
+    if args.monitor {
+        match Monitor::new(daemon.msr.as_ref(), &platform_info) {
+            Ok(monitor) => start_monitor(&daemon, monitor, &args),
+            Err(e) => warn!("Unable to start monitoring: {e}"),
+        }
+    }
+    watch_power_source(bus, power_source, {
+        let daemon = daemon.clone();
+        move |source| {
+            info!("Power source changed to {source}");
+            daemon.set_power_source(source);
+        }
+    });
+
+    glib::unix_signal_add_local(libc::SIGHUP, {
+        let daemon = daemon.clone();
+        move || {
+            daemon.reload();
+            ControlFlow::Continue
+        }
+    });
+    if autoreload {
+        let daemon = daemon.clone();
+        let watched = watch_config(&args.config, move || {
+            if daemon.settings.borrow().config.general.autoreload {
+                daemon.reload();
+            }
+        });
+        if let Err(e) = watched {
+            warn!("Unable to watch {}: {e}", args.config.display());
+        }
+    }
+    let watched = watch_cpu_online({
+        let daemon = daemon.clone();
+        move |cpus| daemon.cpus_online(cpus)
+    });
+    if let Err(e) = watched {
+        warn!("Unable to watch for cpus coming online: {e}");
+    }
 
     // start glib loop
     let main_loop = MainLoop::new(None, false);
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let main_loop = main_loop.clone();
+        glib::unix_signal_add_local(signal, move || {
+            main_loop.quit();
+            ControlFlow::Continue
+        });
+    }
     main_loop.run();
+    daemon.shutdown();
+    Ok(())
 }
@@ -1,5 +1,7 @@
 use rsthrottled::main_loop;
 
 fn main() {
-    main_loop();
+    if let Err(e) = main_loop() {
+        std::process::exit(e.exit_code());
+    }
 }
@@ -1,6 +1,29 @@
-use rsthrottled::{calc_icc_max_msr, calc_time_window_vars};
+use rsthrottled::{
+    apply_regs, calc_critical_temp, calc_ctdp_control, calc_energy_delta, calc_energy_unit,
+    calc_icc_max_msr, calc_pkg_power_limit, calc_rapl_units, calc_temperature_target,
+    calc_time_window_vars, calc_undervolt_msr, calc_undervolt_mv, config_diff, decode_msr,
+    get_icc_max, get_undervolt, parse_args_from, parse_config, parse_cpu_list,
+    parse_cpu_online_uevent, plan_writes, read_mchbar_power_limit, readmsr, readmsr_flat,
+    scope_cpus, set_disable_bdprochot, set_hwp, set_icc_max, set_undervolt, sysfs_power_source,
+    write_mchbar_power_limit, writemsr, Daemon, DevMsr, DryRunMsr, EnergySampler, Error, FakeMsr,
+    Mmio, Monitor, MsrBackend, MsrScope, MsrValue, MsrWriteError, ParsedArgs, PlannedWrite,
+    PlatformInfo, PowerSource, RegisterDump, RegisterSnapshot, Settings,
+};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+const VOLTAGE_PLANE_NAMES: [&str; 5] = ["CORE", "GPU", "CACHE", "UNCORE", "ANALOGIO"];
+
+#[derive(Deserialize)]
+struct UndervoltTest {
+    plane: String,
+    mv: f64,
+    expected_hex: String,
+}
 #[derive(Deserialize)]
 struct IccMaxTest {
     plane: String,
@@ -16,21 +39,148 @@ struct TimeTest {
 }
 #[derive(Deserialize)]
 struct TruthData {
-    undervolt: Vec<serde_json::Value>, // already handled
+    undervolt: Vec<UndervoltTest>,
     iccmax: Vec<IccMaxTest>,
     time_windows: Vec<TimeTest>,
 }
 const JSON_PATH: &str = "tests/fixtures/truth_data.json";
 
+/// A path under the temp dir for files a test creates, removed with them when dropped.
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let name = format!("rsthrottled-{name}-{}", std::process::id());
+        TempPath(std::env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0).or_else(|_| std::fs::remove_file(&self.0));
+    }
+}
+
+#[test]
+fn test_undervolt() {
+    let data = std::fs::read_to_string(JSON_PATH).unwrap();
+    let truth: TruthData = serde_json::from_str(&data).unwrap();
+
+    let fake = FakeMsr::new(2);
+    fake.set(MSR_OC_MAILBOX, 0);
+    for t in truth.undervolt {
+        let actual = calc_undervolt_msr(&t.plane, t.mv).unwrap();
+        let expected = u64::from_str_radix(t.expected_hex.trim_start_matches("0x"), 16).unwrap();
+        assert_eq!(
+            actual, expected,
+            "Undervolt fail: {}mV on {}",
+            t.mv, t.plane
+        );
+
+        let plane = *VOLTAGE_PLANE_NAMES.iter().find(|p| **p == t.plane).unwrap();
+        let applied = set_undervolt(&fake, &vec![], &HashMap::from([(plane, t.mv)]));
+        let applied: Vec<_> = applied
+            .into_iter()
+            .map(|(plane, result)| (plane, result.map_err(|e| e.to_string())))
+            .collect();
+        assert_eq!(applied, vec![(plane, Ok(t.mv as i64))]);
+        let read = get_undervolt(&fake, &vec![], Some(plane), true, Default::default()).unwrap();
+        assert_eq!(read[plane], t.mv as i64);
+    }
+    // Wrapping around would turn these into overvolts.
+    for mv in [-1000.0, -1500.0, 10.0, f64::NAN] {
+        let err = calc_undervolt_msr("CORE", mv).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{mv}: {err:?}");
+    }
+    let deepest = calc_undervolt_msr("CORE", -999.0).unwrap();
+    assert_eq!(calc_undervolt_mv(deepest), -999);
+    let applied = set_undervolt(&fake, &vec![], &HashMap::from([("CORE", -1500.0)]));
+    assert!(applied[0].1.is_err(), "{applied:?}");
+
+    let err = calc_undervolt_msr("DISK", -10.0).unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+}
+
+#[test]
+fn test_exit_codes() {
+    assert_eq!(Error::Usage("--raw".to_owned()).exit_code(), 2);
+    assert_eq!(Error::Config("no [AC]".to_owned()).exit_code(), 3);
+    assert_eq!(Error::NotRoot.exit_code(), 4);
+    assert_eq!(Error::MsrModule.exit_code(), 5);
+    assert_eq!(Error::UnsupportedCpu("AMD".to_owned()).exit_code(), 6);
+    assert_eq!(Error::InvalidArgument("DISK".to_owned()).exit_code(), 1);
+
+    let denied = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+    let read = Error::Read {
+        register: "MSR_OC_MAILBOX",
+        source: denied(),
+    };
+    assert_eq!(read.exit_code(), 4);
+    let write = Error::Write {
+        register: "MSR_OC_MAILBOX",
+        source: MsrWriteError::Io {
+            cpu: 0,
+            source: denied(),
+        },
+    };
+    assert_eq!(write.exit_code(), 4);
+    let write = Error::Write {
+        register: "MSR_OC_MAILBOX",
+        source: MsrWriteError::Io {
+            cpu: 0,
+            source: std::io::Error::other("busy"),
+        },
+    };
+    assert_eq!(write.exit_code(), 1);
+}
+
 #[test]
 fn test_iccmax() {
     let data = std::fs::read_to_string(JSON_PATH).unwrap();
     let truth: TruthData = serde_json::from_str(&data).unwrap();
 
+    let fake = FakeMsr::new(2);
+    fake.set(MSR_OC_MAILBOX, 0);
     for t in truth.iccmax {
-        let actual = calc_icc_max_msr(&t.plane, t.amp);
+        let actual = calc_icc_max_msr(&t.plane, t.amp).unwrap();
         let expected = u64::from_str_radix(t.expected_hex.trim_start_matches("0x"), 16).unwrap();
         assert_eq!(actual, expected, "IccMax fail: {}A on {}", t.amp, t.plane);
+
+        let plane = *VOLTAGE_PLANE_NAMES.iter().find(|p| **p == t.plane).unwrap();
+        let applied = set_icc_max(&fake, &HashMap::from([(plane, t.amp)]));
+        let applied: Vec<_> = applied
+            .into_iter()
+            .map(|(plane, result)| (plane, result.map_err(|e| e.to_string())))
+            .collect();
+        assert_eq!(applied, vec![(plane, Ok(t.amp))]);
+        assert_eq!(get_icc_max(&fake, Some(plane)).unwrap()[plane], t.amp);
+    }
+
+    // 0x3FF quarter amps is the largest encodable limit.
+    assert_eq!(
+        calc_icc_max_msr("CACHE", 255.75).unwrap(),
+        0x80000217000003FF
+    );
+    let applied = set_icc_max(&fake, &HashMap::from([("CACHE", 255.75)]));
+    assert_eq!(applied[0].1.as_ref().ok(), Some(&255.75));
+    for (amp, accepted) in [("255.75", true), ("255.76", false), ("nan", false)] {
+        let config = format!("[GENERAL]\n[AC]\nUpdate_Rate_s: 5\n[BATTERY]\nUpdate_Rate_s: 5\n[ICCMAX]\nCACHE: {amp}\n");
+        let (config, _) = parse_config(&config).unwrap();
+        assert_eq!(config.ac.iccmax.contains_key("CACHE"), accepted, "{amp}");
     }
 }
 
@@ -48,4 +198,803 @@ fn test_time_windows() {
             t.input_sec
         );
     }
+    // Longer than 2^31 * 1.75 time units: the longest window.
+    assert_eq!(calc_time_window_vars(1e9, 0.0009765625), (31, 3));
+}
+
+const MSR_OC_MAILBOX: u64 = 0x150;
+const MSR_PKG_POWER_LIMIT: u64 = 0x610;
+
+fn round_trip(msr: &dyn MsrBackend) {
+    writemsr(msr, "MSR_PKG_POWER_LIMIT", 0x42816000DD8138).unwrap();
+    for cpu in msr.cpus() {
+        assert_eq!(
+            msr.read(cpu, MSR_PKG_POWER_LIMIT).unwrap(),
+            0x42816000DD8138
+        );
+    }
+    let actual = readmsr_flat(msr, "MSR_PKG_POWER_LIMIT", None, None).unwrap();
+    assert_eq!(actual, 0x42816000DD8138);
+}
+
+#[test]
+fn test_msr_round_trip() {
+    let fake = FakeMsr::new(4);
+    fake.set(MSR_PKG_POWER_LIMIT, 0);
+    round_trip(&fake);
+
+    let root = TempPath::new("msr");
+    for cpu in 0..2 {
+        let dir = root.join(cpu.to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::File::create(dir.join("msr"))
+            .unwrap()
+            .set_len(0x1000)
+            .unwrap();
+    }
+    round_trip(&DevMsr::with_root(&*root, 2));
+}
+
+#[test]
+fn test_writemsr_rollback() {
+    let fake = FakeMsr::new(4);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x1234);
+    fake.lock(2, MSR_PKG_POWER_LIMIT);
+
+    let err = writemsr(&fake, "MSR_PKG_POWER_LIMIT", 0x5678).unwrap_err();
+    assert!(matches!(
+        err,
+        MsrWriteError::Verify {
+            cpu: 2,
+            found: 0x1234,
+            ..
+        }
+    ));
+    for cpu in 0..4 {
+        assert_eq!(fake.get(cpu, MSR_PKG_POWER_LIMIT), Some(0x1234));
+    }
+
+    // The mailbox isn't rolled back: cpu 0 keeps the response to the command, its previous
+    // response isn't sent back as a command.
+    struct RefusingCpu1(FakeMsr);
+    impl MsrBackend for RefusingCpu1 {
+        fn cpus(&self) -> Vec<usize> {
+            self.0.cpus()
+        }
+        fn read(&self, cpu: usize, addr: u64) -> std::io::Result<u64> {
+            self.0.read(cpu, addr)
+        }
+        fn write(&self, cpu: usize, addr: u64, value: u64) -> std::io::Result<()> {
+            if cpu == 1 {
+                return Err(std::io::Error::other("refused"));
+            }
+            self.0.write(cpu, addr, value)
+        }
+    }
+    let fake = RefusingCpu1(FakeMsr::new(2));
+    fake.0.write(0, MSR_OC_MAILBOX, 0x8000001100000000).unwrap();
+    let command = calc_undervolt_msr("CORE", -50.0).unwrap();
+    let err = writemsr(&fake, "MSR_OC_MAILBOX", command).unwrap_err();
+    assert!(matches!(err, MsrWriteError::Io { cpu: 1, .. }), "{err:?}");
+    assert_eq!(fake.0.get(0, MSR_OC_MAILBOX), Some(command & 0xFFFFFFFF));
+}
+
+const CONFIG: &str = "
+[GENERAL]
+Enabled: True
+
+[BATTERY]
+Update_Rate_s: 0
+PL1_Tdp_W: 29
+Trip_Temp_C: 120
+HWP_Mode: False
+
+[AC]
+Update_Rate_s: 5
+PL1_Tdp_W: 44
+PL2_Duration_S: 0.002
+Trip_Temp_C: 95
+
+[UNDERVOLT.AC]
+CORE: -100
+GPU: 20
+CACHE: -100
+
+[ICCMAX]
+CORE: 64
+GPU: 300
+";
+
+#[test]
+fn test_load_config() {
+    let (config, warnings) = parse_config(CONFIG).unwrap();
+    assert_eq!(config.battery.update_rate_s, 0.001);
+    assert_eq!(config.battery.trip_temp_c, Some(97.0));
+    assert_eq!(config.battery.hwp_mode, Some(false));
+    assert_eq!(config.ac.pl2_duration_s, Some(0.002));
+    assert_eq!(config.ac.pl2_tdp_w, None);
+    assert_eq!(config.ac.undervolt["GPU"], 0.0);
+    assert_eq!(config.ac.undervolt["CORE"], -100.0);
+    assert_eq!(config.battery.undervolt["CORE"], 0.0);
+    assert_eq!(config.battery.iccmax["CORE"], 64.0);
+    assert!(!config.battery.iccmax.contains_key("GPU"));
+    assert_eq!(warnings.len(), 4, "{warnings:?}");
+
+    let missing_rate = CONFIG.replace("Update_Rate_s: 5", "");
+    assert!(parse_config(&missing_rate).is_err());
+    for rate in ["inf", "NaN", "1e300", "86401"] {
+        let rate = CONFIG.replace("Update_Rate_s: 5", &format!("Update_Rate_s: {rate}"));
+        let err = parse_config(&rate).unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{err:?}");
+    }
+    let huge_limit = CONFIG.replace("PL1_Tdp_W: 44", "PL1_Tdp_W: inf");
+    assert!(parse_config(&huge_limit).is_err());
+    let nan_trip_temp = CONFIG.replace("Trip_Temp_C: 95", "Trip_Temp_C: nan");
+    assert!(parse_config(&nan_trip_temp).is_err());
+    let nan_undervolt = CONFIG.replace("CORE: -100", "CORE: nan");
+    assert!(parse_config(&nan_undervolt).is_err());
+    // Too deep to encode: ignored rather than wrapped around into an overvolt.
+    let deep_undervolt = CONFIG.replace("CORE: -100", "CORE: -1500");
+    let (deep_undervolt, warnings) = parse_config(&deep_undervolt).unwrap();
+    assert_eq!(deep_undervolt.ac.undervolt["CORE"], 0.0);
+    assert!(warnings.iter().any(|w| w.contains("-1500")), "{warnings:?}");
+
+    assert!(config_diff(&config, &config).is_empty());
+    let edited = CONFIG
+        .replace("PL1_Tdp_W: 44", "PL1_Tdp_W: 35")
+        .replace("CACHE: -100", "");
+    let (edited, _) = parse_config(&edited).unwrap();
+    assert_eq!(
+        config_diff(&config, &edited),
+        ["AC.PL1_Tdp_W: 44 -> 35", "UNDERVOLT.AC.CACHE: -100 -> 0"]
+    );
+}
+
+#[test]
+fn test_pkg_power_limit() {
+    let (config, _) = parse_config(
+        "[AC]\nUpdate_Rate_s: 5\nPL1_Tdp_W: 44\nPL1_Duration_s: 28\nPL2_Tdp_W: 44\nPL2_Duration_S: 0.002\n\
+         [BATTERY]\nUpdate_Rate_s: 30\nPL1_Tdp_W: 29\n",
+    )
+    .unwrap();
+    // 1/8 W power unit, 1/1024 s time unit, as on a T480s
+    let (power_unit, time_unit) = calc_rapl_units(0xA0E03);
+    assert_eq!((power_unit, time_unit), (0.125, 0.0009765625));
+
+    let ac = calc_pkg_power_limit(&config.ac, 0, power_unit, time_unit);
+    assert_eq!(ac, 0x43816000DD8160);
+    // PL1 changes, everything else is kept from the current value
+    let battery = calc_pkg_power_limit(&config.battery, ac, power_unit, time_unit);
+    assert_eq!(battery, 0x43816000DD80E8);
+
+    // 5000 W doesn't fit the 15 bit field, it gets the largest limit rather than wrapping.
+    let mut huge = config.battery.clone();
+    huge.pl1_tdp_w = Some(5000.0);
+    let limit = calc_pkg_power_limit(&huge, ac, power_unit, time_unit);
+    assert_eq!(limit & 0x7FFF, 0x7FFF);
+    assert_eq!(limit >> 15, ac >> 15);
+}
+
+#[test]
+fn test_mchbar_power_limit() {
+    let (config, _) = parse_config(CONFIG).unwrap();
+    let (power_unit, time_unit) = calc_rapl_units(0xA0E03);
+    let value = calc_pkg_power_limit(&config.ac, 0, power_unit, time_unit);
+
+    // A file stands in for /dev/mem, the mirror sits at a page offset like 0xFED159A0 does.
+    let path = TempPath::new("mchbar");
+    std::fs::File::create(&path)
+        .unwrap()
+        .set_len(0x2000)
+        .unwrap();
+    let mchbar = Mmio::map(&path, 0x19A0, 8).unwrap();
+    write_mchbar_power_limit(&mchbar, value).unwrap();
+    assert_eq!(read_mchbar_power_limit(&mchbar).unwrap(), value);
+    drop(mchbar);
+    let mem = std::fs::read(&path).unwrap();
+    assert_eq!(mem[0x19A0..0x19A8], value.to_le_bytes());
+
+    let mchbar = Mmio::map(&path, 0x19A0, 8).unwrap();
+    assert!(mchbar.read32(8).is_err());
+    assert!(mchbar.write32(2, 0).is_err());
+}
+
+#[test]
+fn test_temperature_target() {
+    // TjMax 100 'C, locked bit clear, some reserved bits set
+    let current = 0x0064_0F00;
+    assert_eq!(calc_critical_temp(current), 100.0);
+    assert_eq!(calc_temperature_target(current, 85.0), 0x0F64_0F00);
+    assert_eq!(calc_temperature_target(0x0F64_0F00, 97.0), 0x0364_0F00);
+
+    // TjMax reads 0 on VMs: Trip_Temp_C is skipped, the rest of the profile still applies.
+    let fake = FakeMsr::new(1);
+    fake.set(0x606, 0xA0E03);
+    fake.set(0x1A2, 0);
+    fake.set(MSR_PKG_POWER_LIMIT, 0);
+    let info = PlatformInfo::from_msr(0x4040470001500);
+    let path = TempPath::new("tjmax");
+    std::fs::write(&path, CONFIG).unwrap();
+    let (settings, _) = Settings::load(&fake, &path, &info).unwrap();
+    let regs = &settings.regs[&PowerSource::Ac];
+    assert!(!regs.contains_key("MSR_TEMPERATURE_TARGET"), "{regs:?}");
+    assert!(regs.contains_key("MSR_PKG_POWER_LIMIT"), "{regs:?}");
+}
+
+#[test]
+fn test_platform_info() {
+    let info = PlatformInfo::from_msr(0x4040470001500);
+    assert_eq!(info.maximum_non_turbo_ratio, 0x15);
+    assert!(info.programmable_ratio_limit);
+    assert!(info.programmable_tdp_limit);
+    assert!(info.programmable_tj_offset);
+    assert!(!info.ppin_cap && !info.low_power_mode);
+    assert_eq!(info.config_tdp_levels, 2);
+    assert_eq!(info.maximum_efficiency_ratio, 4);
+    assert_eq!(info.minimum_operating_ratio, 4);
+}
+
+#[test]
+fn test_ctdp_control() {
+    // Level 0 selected, lock bit and reserved bits set
+    let current = 0x8000_0F00;
+    assert_eq!(calc_ctdp_control(current, 2), 0x8000_0F02);
+    assert_eq!(calc_ctdp_control(0x8000_0F02, 1), 0x8000_0F01);
+    // Only the two level bits are written
+    assert_eq!(calc_ctdp_control(current, 0xFD), 0x8000_0F01);
+}
+
+#[test]
+fn test_energy_sampler() {
+    assert_eq!(calc_energy_unit(0xA0E03), 1.0 / 16384.0);
+    assert_eq!(calc_energy_delta(0x1000, 0x3000), 0x2000);
+    assert_eq!(calc_energy_delta(0xFFFF_F000, 0x1000), 0x2000);
+
+    let fake = FakeMsr::new(2);
+    fake.set(0x606, 0xA0E03);
+    fake.set(0x611, 0xFFFF_0000);
+    fake.set(0x641, 0);
+    // No DRAM energy counter on this CPU.
+    let mut sampler = EnergySampler::new(&fake, 3).unwrap();
+    assert_eq!(
+        sampler.planes().collect::<Vec<_>>(),
+        ["Package", "Graphics"]
+    );
+
+    let start = Instant::now();
+    sampler.sample_at(&fake, start).unwrap();
+    assert_eq!(sampler.watts("Package"), None);
+    // 10 W then 20 W over 1 s intervals, the package counter wrapping in between.
+    for (secs, package) in [(1, 0x0001_8000), (2, 0x0006_8000)] {
+        fake.set(0x611, package);
+        sampler
+            .sample_at(&fake, start + Duration::from_secs(secs))
+            .unwrap();
+    }
+    assert_eq!(sampler.watts("Package"), Some(20.0));
+    assert_eq!(sampler.average_watts("Package"), Some(15.0));
+    assert_eq!(sampler.average_watts("Graphics"), Some(0.0));
+    assert_eq!(sampler.watts("DRAM"), None);
+
+    // The window keeps the last three samples only.
+    fake.set(0x611, 0x0006_8000 + 30 * 16384);
+    sampler
+        .sample_at(&fake, start + Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(sampler.average_watts("Package"), Some(25.0));
+}
+
+#[test]
+fn test_register_snapshot() {
+    let fake = FakeMsr::new(2);
+    fake.set(MSR_OC_MAILBOX, 0);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x42816000DD8118);
+    fake.set(0x774, 0x80002A0A);
+    fake.set(0x1FC, 0x0024_005F);
+    let no_unsupported = vec![];
+    // -50 mV, with low bits set by the firmware that a mV round trip would drop.
+    fake.write(0, MSR_OC_MAILBOX, 0x80000011F9A00005).unwrap();
+    set_icc_max(&fake, &HashMap::from([("CORE", 64.0)]));
+    let snapshot = RegisterSnapshot::take(&fake, &no_unsupported);
+    // Only the registers this fake implements are kept.
+    assert_eq!(
+        snapshot.msrs,
+        [
+            (
+                "MSR_PKG_POWER_LIMIT",
+                vec![(0, 0x42816000DD8118), (1, 0x42816000DD8118)]
+            ),
+            ("MSR_POWER_CTL", vec![(0, 0x0024_005F), (1, 0x0024_005F)]),
+            ("IA32_HWP_REQUEST", vec![(0, 0x80002A0A), (1, 0x80002A0A)])
+        ]
+    );
+    assert_eq!(snapshot.undervolt["CORE"], 0xF9A00005);
+    assert_eq!(snapshot.undervolt["GPU"], 0);
+
+    writemsr(&fake, "MSR_PKG_POWER_LIMIT", 0x43816000DD8160).unwrap();
+    // BDPROCHOT and EPP are changed by the daemon, the other bits by the kernel meanwhile.
+    fake.write(1, 0x774, 0x20001E08).unwrap();
+    fake.write(1, 0x1FC, 0x0020_005E).unwrap();
+    set_undervolt(
+        &fake,
+        &no_unsupported,
+        &HashMap::from([("CORE", -100.0), ("GPU", -20.0)]),
+    );
+    set_icc_max(&fake, &HashMap::from([("CORE", 80.0)]));
+    snapshot.restore(&fake, &no_unsupported);
+    assert_eq!(fake.get(1, 0x774), Some(0x80001E08));
+    assert_eq!(fake.get(1, 0x1FC), Some(0x0020_005F));
+    fake.write(1, 0x774, 0x80002A0A).unwrap();
+    fake.write(1, 0x1FC, 0x0024_005F).unwrap();
+    assert_eq!(RegisterSnapshot::take(&fake, &no_unsupported), snapshot);
+}
+
+#[test]
+fn test_dry_run() {
+    let fake = FakeMsr::new(2);
+    fake.set(MSR_OC_MAILBOX, 0);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x42816000DD8118);
+    let cache = calc_undervolt_msr("CACHE", -50.0).unwrap();
+    fake.write(0, MSR_OC_MAILBOX, cache).unwrap();
+    let dry_run = DryRunMsr::new(fake);
+    writemsr(&dry_run, "MSR_PKG_POWER_LIMIT", 0x43816000DD8160).unwrap();
+    let undervolt = HashMap::from([("CORE", -100.0), ("GPU", 0.0)]);
+    for (plane, result) in set_undervolt(&dry_run, &vec![], &undervolt) {
+        assert!(result.is_ok(), "{plane}: {result:?}");
+    }
+    assert_eq!(
+        dry_run.inner().get(1, MSR_PKG_POWER_LIMIT),
+        Some(0x42816000DD8118)
+    );
+    // No mailbox command reached the fake, which still holds its reply to the CACHE write, and
+    // planes without a planned write can't be read.
+    let err =
+        get_undervolt(&dry_run, &vec![], Some("CACHE"), true, Default::default()).unwrap_err();
+    assert!(matches!(err, Error::Read { .. }), "{err:?}");
+    assert_eq!(
+        dry_run.inner().get(0, MSR_OC_MAILBOX),
+        Some(cache & 0xFFFFFFFF)
+    );
+    assert_eq!(
+        get_undervolt(
+            dry_run.inner(),
+            &vec![],
+            Some("CORE"),
+            true,
+            Default::default()
+        )
+        .unwrap()["CORE"],
+        0
+    );
+    // The mailbox isn't read, so both planes are listed even though GPU is already at 0 mV.
+    let plan = plan_writes(&dry_run).unwrap();
+    assert_eq!(
+        plan,
+        [
+            PlannedWrite {
+                register: "MSR_OC_MAILBOX",
+                before: None,
+                after: calc_undervolt_msr("CORE", -100.0).unwrap(),
+            },
+            PlannedWrite {
+                register: "MSR_OC_MAILBOX",
+                before: None,
+                after: calc_undervolt_msr("GPU", 0.0).unwrap(),
+            },
+            PlannedWrite {
+                register: "MSR_PKG_POWER_LIMIT",
+                before: Some(0x42816000DD8118),
+                after: 0x43816000DD8160,
+            },
+        ]
+    );
+    let fields = decode_msr("MSR_PKG_POWER_LIMIT", plan[2].after, 0xA0E03);
+    assert_eq!(fields[0], ("PL1", "44 W".to_owned()));
+    assert_eq!(fields[4], ("PL2", "44 W".to_owned()));
+    let fields = decode_msr("MSR_OC_MAILBOX", plan[0].after, 0xA0E03);
+    assert_eq!(
+        fields,
+        [
+            ("Plane", "CORE".to_owned()),
+            ("Offset", "-100 mV".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn test_register_dump() {
+    let fake = FakeMsr::new(4);
+    fake.set(MSR_OC_MAILBOX, 0);
+    fake.set(0x606, 0xA0E03);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x43816000DD8160);
+    fake.set(0x774, 0x80002A0A);
+    fake.write(2, 0x774, 0x00002A0A).unwrap();
+    set_undervolt(&fake, &vec![], &HashMap::from([("CORE", -100.0)]));
+    let dump = RegisterDump::read(&fake);
+
+    let text = dump.to_text(false);
+    assert!(text
+        .contains("MSR_PKG_POWER_LIMIT (0x610)\n  cpu 0-3: 0x0043816000dd8160\n    PL1: 44 W\n"));
+    assert!(text.contains("  cpu 0-1,3: 0x0000000080002a0a\n"), "{text}");
+    assert!(text.contains("  cpu 2: 0x0000000000002a0a\n"));
+    assert!(text.contains("MSR_PLATFORM_INFO (0xce)\n  cpu 0-3: unreadable\n"));
+    assert!(text.contains("Undervolt\n  CORE: -100 mV\n  GPU: 0 mV\n"));
+    assert!(!dump.to_text(true).contains("PL1"));
+
+    let json: serde_json::Value = serde_json::from_str(&dump.to_json(false)).unwrap();
+    let power_limit = &json["registers"]["MSR_PKG_POWER_LIMIT"];
+    assert_eq!(power_limit["address"], "0x610");
+    assert_eq!(power_limit["values"]["3"], "0x43816000dd8160");
+    assert_eq!(power_limit["fields"]["PL2"], "44 W");
+    assert!(json["registers"]["MSR_PLATFORM_INFO"]["values"]["0"].is_null());
+    assert_eq!(json["undervolt_mv"]["CORE"], -100);
+}
+
+#[test]
+fn test_readmsr() {
+    let fake = FakeMsr::new(3);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x43816000DD8160);
+    let pl2 = readmsr(&fake, "MSR_PKG_POWER_LIMIT", Some(32), Some(46), None).unwrap();
+    assert_eq!(pl2, MsrValue::Uniform(0x160));
+    let pl2_enabled = readmsr(&fake, "MSR_PKG_POWER_LIMIT", Some(47), Some(47), None).unwrap();
+    assert_eq!(pl2_enabled, MsrValue::Uniform(1));
+
+    fake.write(1, MSR_PKG_POWER_LIMIT, 0x43816000DD80E8)
+        .unwrap();
+    let pl1 = readmsr(&fake, "MSR_PKG_POWER_LIMIT", None, Some(14), None).unwrap();
+    assert_eq!(
+        pl1,
+        MsrValue::PerCpu(vec![(0, 0x160), (1, 0xE8), (2, 0x160)])
+    );
+    let pl1 = readmsr(&fake, "MSR_PKG_POWER_LIMIT", None, Some(14), Some(1)).unwrap();
+    assert_eq!(pl1, MsrValue::Uniform(0xE8));
+    assert_eq!(
+        readmsr_flat(&fake, "MSR_PKG_POWER_LIMIT", Some(32), None).unwrap(),
+        0x438160
+    );
+
+    for (from, to, cpu) in [
+        (Some(8), Some(7), None),
+        (None, Some(64), None),
+        (None, None, Some(3)),
+    ] {
+        let err = readmsr(&fake, "MSR_PKG_POWER_LIMIT", from, to, cpu).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+    }
+}
+
+#[test]
+fn test_msr_topology() {
+    // cpus 0 and 1 are hyperthreads of one core, cpu 3 is on a second socket.
+    let fake = FakeMsr::new(4);
+    for (cpu, package, core) in [(0, 0, 0), (1, 0, 0), (2, 0, 1), (3, 1, 0)] {
+        fake.set_topology(cpu, package, core);
+    }
+    assert_eq!(scope_cpus(&fake, MsrScope::Thread), [0, 1, 2, 3]);
+    assert_eq!(scope_cpus(&fake, MsrScope::Core), [0, 2, 3]);
+    assert_eq!(scope_cpus(&fake, MsrScope::Package), [0, 3]);
+
+    fake.set(MSR_PKG_POWER_LIMIT, 0x42816000DD8118);
+    writemsr(&fake, "MSR_PKG_POWER_LIMIT", 0x43816000DD8160).unwrap();
+    let values: Vec<_> = (0..4)
+        .map(|cpu| fake.get(cpu, MSR_PKG_POWER_LIMIT))
+        .collect();
+    assert_eq!(
+        values,
+        [
+            Some(0x43816000DD8160),
+            Some(0x42816000DD8118),
+            Some(0x42816000DD8118),
+            Some(0x43816000DD8160)
+        ]
+    );
+    let pl1 = readmsr(&fake, "MSR_PKG_POWER_LIMIT", None, Some(14), None).unwrap();
+    assert_eq!(pl1, MsrValue::Uniform(0x160));
+
+    fake.write(3, MSR_PKG_POWER_LIMIT, 0x43816000DD80E8)
+        .unwrap();
+    let pl1 = readmsr(&fake, "MSR_PKG_POWER_LIMIT", None, Some(14), None).unwrap();
+    assert_eq!(pl1, MsrValue::PerCpu(vec![(0, 0x160), (3, 0xE8)]));
+
+    // cpu 1 has an unknown topology, it isn't mistaken for the one of package 1.
+    let fake = FakeMsr::new(3);
+    fake.set_topology(0, 0, 0);
+    fake.set_topology(2, 1, 0);
+    assert_eq!(scope_cpus(&fake, MsrScope::Package), [0, 1, 2]);
+    assert_eq!(scope_cpus(&fake, MsrScope::Core), [0, 1, 2]);
+}
+
+#[test]
+fn test_offline_cpus() {
+    assert_eq!(parse_cpu_list("0-2,4,6-7\n").unwrap(), [0, 1, 2, 4, 6, 7]);
+    assert_eq!(parse_cpu_list("0").unwrap(), [0]);
+    for list in ["0-", "3-1", "a"] {
+        assert!(matches!(
+            parse_cpu_list(list),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    let fake = FakeMsr::new(4);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x42816000DD8118);
+    fake.set_online(2, false);
+    assert_eq!(fake.cpus(), [0, 1, 3]);
+    writemsr(&fake, "MSR_PKG_POWER_LIMIT", 0x43816000DD8160).unwrap();
+    assert_eq!(
+        readmsr_flat(&fake, "MSR_PKG_POWER_LIMIT", None, None).unwrap(),
+        0x43816000DD8160
+    );
+    let err = readmsr(&fake, "MSR_PKG_POWER_LIMIT", None, None, Some(2)).unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{err:?}");
+
+    // Back online with the value it had when it went offline.
+    fake.set_online(2, true);
+    assert_eq!(fake.get(2, MSR_PKG_POWER_LIMIT), Some(0x42816000DD8118));
+    let dump = RegisterDump::read(&fake).to_text(true);
+    assert!(dump.contains("  cpu 0-1,3: 0x0043816000dd8160\n  cpu 2: 0x0042816000dd8118\n"));
+}
+
+#[test]
+fn test_parse_args() {
+    let parse = |args: &[&str]| parse_args_from(args.iter().map(|arg| arg.to_string()));
+    let Ok(ParsedArgs::Run(config)) = parse(&["--debug", "--config=/tmp/t.conf", "--monitor"])
+    else {
+        panic!("expected a config");
+    };
+    assert!(config.debug && config.monitor && !config.force);
+    assert_eq!(config.config.to_str(), Some("/tmp/t.conf"));
+    assert_eq!(config.monitor_ms, 1000);
+    let Ok(ParsedArgs::Run(config)) = parse(&["--monitor", "0.01", "--force"]) else {
+        panic!("expected a config");
+    };
+    assert_eq!(config.monitor_ms, 100);
+    assert!(config.force);
+    let Ok(ParsedArgs::Run(config)) = parse(&["regs", "--json"]) else {
+        panic!("expected a config");
+    };
+    assert!(config.regs && config.json);
+
+    assert!(matches!(parse(&["--debug", "-h"]), Ok(ParsedArgs::Help)));
+    assert!(matches!(parse(&["--version"]), Ok(ParsedArgs::Version)));
+    assert_eq!(
+        parse(&["--monitor", "-1"]).unwrap_err(),
+        "unrecognized arguments: -1"
+    );
+    assert_eq!(
+        parse(&["--monitor", "fast"]).unwrap_err(),
+        "argument --monitor: invalid update rate: 'fast'"
+    );
+    assert_eq!(
+        parse(&["--config"]).unwrap_err(),
+        "argument --config: expected one argument"
+    );
+    assert_eq!(
+        parse(&["--raw"]).unwrap_err(),
+        "argument --raw: only valid with regs"
+    );
+    assert_eq!(
+        parse(&["regs", "--dry-run"]).unwrap_err(),
+        "argument --dry-run: not valid with regs"
+    );
+    assert_eq!(
+        parse(&["--monitor", "2", "regs"]).unwrap_err(),
+        "argument --monitor: not valid with regs"
+    );
+}
+
+#[test]
+fn test_sysfs_power_source() {
+    let root = TempPath::new("power");
+    let supply = |name: &str, kind: &str, online: Option<&str>| {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("type"), format!("{kind}\n")).unwrap();
+        if let Some(online) = online {
+            std::fs::write(dir.join("online"), format!("{online}\n")).unwrap();
+        }
+    };
+    // No supply at all is assumed to be AC.
+    assert_eq!(sysfs_power_source(&root), PowerSource::Ac);
+    supply("BAT0", "Battery", None);
+    assert_eq!(sysfs_power_source(&root), PowerSource::Ac);
+    supply("AC", "Mains", Some("0"));
+    supply("ucsi-source-psy-USBC000:001", "USB", Some("0"));
+    assert_eq!(sysfs_power_source(&root), PowerSource::Battery);
+    supply("ucsi-source-psy-USBC000:001", "USB", Some("1"));
+    assert_eq!(sysfs_power_source(&root), PowerSource::Ac);
+}
+
+#[test]
+fn test_hwp() {
+    const IA32_HWP_REQUEST: u64 = 0x774;
+    // Each cpu has its own min/max/desired performance, with EPP 0x80.
+    let requests = [0x80002A0A, 0x80001E08, 0x80FF2A0A];
+    let fake = FakeMsr::new(3);
+    for (cpu, request) in requests.into_iter().enumerate() {
+        fake.write(cpu, IA32_HWP_REQUEST, request).unwrap();
+    }
+    let snapshot = RegisterSnapshot::take(&fake, &vec![]);
+
+    set_hwp(&fake, 0x20).unwrap();
+    for (cpu, request) in requests.into_iter().enumerate() {
+        let expected = (request & !0xFF00_0000) | 0x2000_0000;
+        assert_eq!(fake.get(cpu, IA32_HWP_REQUEST), Some(expected), "cpu {cpu}");
+    }
+
+    snapshot.restore(&fake, &vec![]);
+    for (cpu, request) in requests.into_iter().enumerate() {
+        assert_eq!(fake.get(cpu, IA32_HWP_REQUEST), Some(request), "cpu {cpu}");
+    }
+}
+
+#[test]
+fn test_disable_bdprochot() {
+    const MSR_POWER_CTL: u64 = 0x1FC;
+    // cpus 0-1 and 2-3 are two cores
+    let fake = FakeMsr::new(4);
+    for cpu in 0..4 {
+        fake.set_topology(cpu, 0, cpu / 2);
+    }
+    fake.set(MSR_POWER_CTL, 0x0024_005F);
+    assert_eq!(set_disable_bdprochot(&fake).unwrap(), [0, 2]);
+    assert_eq!(fake.get(0, MSR_POWER_CTL), Some(0x0024_005E));
+    assert_eq!(fake.get(2, MSR_POWER_CTL), Some(0x0024_005E));
+    assert!(set_disable_bdprochot(&fake).unwrap().is_empty());
+
+    // Firmware sets it back on the second core only.
+    fake.write(2, MSR_POWER_CTL, 0x0024_005F).unwrap();
+    assert_eq!(set_disable_bdprochot(&fake).unwrap(), [2]);
+    assert_eq!(fake.get(2, MSR_POWER_CTL), Some(0x0024_005E));
+
+    fake.write(0, MSR_POWER_CTL, 0x0024_005F).unwrap();
+    fake.lock(0, MSR_POWER_CTL);
+    let err = set_disable_bdprochot(&fake).unwrap_err();
+    assert!(matches!(err, Error::Write { .. }), "{err:?}");
+}
+
+#[test]
+fn test_apply_regs() {
+    const MSR_TEMPERATURE_TARGET: u64 = 0x1A2;
+    let fake = FakeMsr::new(2);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x43816000DD8160);
+    fake.set(MSR_TEMPERATURE_TARGET, 0x0364_0F00);
+    let regs = HashMap::from([
+        ("MSR_PKG_POWER_LIMIT", 0x43816000DD8160),
+        ("MSR_TEMPERATURE_TARGET", 0x0364_0F00),
+    ]);
+    assert_eq!(apply_regs(&fake, &regs), 0);
+
+    // Firmware reset PL1 on cpu 1 only.
+    fake.write(1, MSR_PKG_POWER_LIMIT, 0x43816000DD80E8)
+        .unwrap();
+    assert_eq!(apply_regs(&fake, &regs), 1);
+    assert_eq!(fake.get(1, MSR_PKG_POWER_LIMIT), Some(0x43816000DD8160));
+    assert_eq!(apply_regs(&fake, &regs), 0);
+}
+
+#[test]
+fn test_reload() {
+    let fake = FakeMsr::new(2);
+    fake.set(0x606, 0xA0E03);
+    fake.set(0x1A2, 0x0064_0000);
+    fake.set(MSR_PKG_POWER_LIMIT, 0);
+    let info = PlatformInfo::from_msr(0x4040470001500);
+    let path = TempPath::new("reload");
+    std::fs::write(&path, CONFIG).unwrap();
+    let (mut settings, _) = Settings::load(&fake, &path, &info).unwrap();
+    let loaded = settings.clone();
+
+    std::fs::write(&path, CONFIG.replace("Update_Rate_s: 5", "")).unwrap();
+    assert!(settings.reload(&fake, &path, &info).is_err());
+    assert_eq!(settings, loaded);
+
+    std::fs::write(&path, CONFIG.replace("PL1_Tdp_W: 44", "PL1_Tdp_W: 35")).unwrap();
+    let diff = settings.reload(&fake, &path, &info).unwrap();
+    assert_eq!(diff, ["AC.PL1_Tdp_W: 44 -> 35"]);
+    let regs = &settings.regs[&PowerSource::Ac];
+    assert_ne!(
+        regs["MSR_PKG_POWER_LIMIT"],
+        loaded.regs[&PowerSource::Ac]["MSR_PKG_POWER_LIMIT"]
+    );
+    assert!(apply_regs(&fake, regs) > 0);
+    assert_eq!(
+        fake.get(0, MSR_PKG_POWER_LIMIT),
+        Some(regs["MSR_PKG_POWER_LIMIT"])
+    );
+}
+
+#[test]
+fn test_cpus_online() {
+    let uevent = b"online@/devices/system/cpu/cpu3\0ACTION=online\0SUBSYSTEM=cpu\0";
+    assert_eq!(parse_cpu_online_uevent(uevent), Some(3));
+    assert_eq!(
+        parse_cpu_online_uevent(b"offline@/devices/system/cpu/cpu3\0ACTION=offline\0"),
+        None
+    );
+    assert_eq!(
+        parse_cpu_online_uevent(b"online@/devices/system/memory/memory3\0"),
+        None
+    );
+    assert_eq!(
+        parse_cpu_online_uevent(b"online@/devices/system/cpu/cpufreq\0"),
+        None
+    );
+
+    const MSR_HWP_REQUEST: u64 = 0x774;
+    let fake = Rc::new(FakeMsr::new(2));
+    fake.set(0x606, 0xA0E03);
+    fake.set(0x1A2, 0x0064_0000);
+    fake.set(MSR_OC_MAILBOX, 0);
+    fake.set(MSR_PKG_POWER_LIMIT, 0x42816000DD8118);
+    fake.set(MSR_HWP_REQUEST, 0x80002A0A);
+    fake.set_online(1, false);
+    let info = PlatformInfo::from_msr(0x4040470001500);
+    let path = TempPath::new("online");
+    std::fs::write(&path, CONFIG).unwrap();
+    let (settings, _) = Settings::load(fake.as_ref(), &path, &info).unwrap();
+    let target = settings.regs[&PowerSource::Ac]["MSR_PKG_POWER_LIMIT"];
+    let daemon = Daemon::new(
+        Box::new(fake.clone()),
+        path.to_path_buf(),
+        settings,
+        info,
+        PowerSource::Ac,
+        vec![],
+        None,
+    );
+
+    // cpu 1 comes online as the firmware left it, and is snapshotted before it is changed.
+    fake.set_online(1, true);
+    daemon.cpus_online(&[1]);
+    assert_eq!(fake.get(1, MSR_PKG_POWER_LIMIT), Some(target));
+    fake.write(1, MSR_HWP_REQUEST, 0x20002A0A).unwrap();
+    daemon.shutdown();
+    for cpu in 0..2 {
+        assert_eq!(fake.get(cpu, MSR_PKG_POWER_LIMIT), Some(0x42816000DD8118));
+        assert_eq!(fake.get(cpu, MSR_HWP_REQUEST), Some(0x80002A0A));
+    }
+}
+
+#[test]
+fn test_monitor() {
+    let setup = |ctdp_readable: bool| {
+        let fake = FakeMsr::new(2);
+        fake.set(0x606, 0xA0E03);
+        fake.set(0x1A2, 0x0064_0000);
+        if ctdp_readable {
+            fake.set(0x64B, 0x1);
+        }
+        // Thermal and power limit status set, their log bits too
+        fake.set(0x19C, 0xF0F);
+        // 35 'C below TjMax
+        fake.set(0x1B1, 35 << 16);
+        // VID 0x1800, 750 mV
+        fake.set(0x198, 0x1800 << 32);
+        fake.set(0x611, 0);
+        fake.set(0x641, 0xFFFF_C000);
+        fake
+    };
+    let fake = setup(true);
+    let platform_info = PlatformInfo::from_msr(0x4040470001500);
+    let mut monitor = Monitor::new(&fake, &platform_info).unwrap();
+
+    let start = Instant::now() + Duration::from_secs(10);
+    monitor.sample_at(&fake, PowerSource::Ac, start).unwrap();
+    // 50 J and 2 J (across the wrap) in 2 s, with 1/16384 J energy units
+    fake.set(0x611, 50 * 16384);
+    fake.set(0x641, 0x4000);
+    let line = monitor
+        .sample_at(&fake, PowerSource::Ac, start + Duration::from_secs(2))
+        .unwrap();
+    assert_eq!(
+        line,
+        "[AC] Thermal: LIM - Power: LIM - Current: OK - Cross-domain (e.g. GPU): OK || \
+         Temp: 65 C - VCore: 750 mV - cTDP: 1 - Package: 25.0 W - Graphics: 1.0 W - Total: 26.0 W"
+    );
+
+    // An unreadable cTDP level is left out rather than failing the line.
+    let fake = setup(false);
+    let mut monitor = Monitor::new(&fake, &platform_info).unwrap();
+    let line = monitor.sample_at(&fake, PowerSource::Ac, start).unwrap();
+    assert!(line.contains("VCore: 750 mV - Package"), "{line}");
 }